@@ -0,0 +1,928 @@
+//! Space group checks, outlier rejection and weighted mean cells
+
+use crate::cell::{from_metric, metric, Cell};
+use crate::niggli;
+use crate::spacegroup::{self, CrystalSystem, Laue};
+
+/// how to proceed with data sets in different space groups
+#[derive(Clone, Copy, PartialEq)]
+pub enum SgPolicy {
+    Fail,
+    Majority,
+    Subgroup,
+}
+
+/// which esu of the weighted mean to report
+#[derive(Clone, Copy, PartialEq)]
+pub enum SuMode {
+    Internal,
+    External,
+    Max,
+    /// a single data set without esu's, whose unit weights give no esu
+    Unknown,
+}
+
+impl SuMode {
+    /// description for the output
+    pub fn describe(&self) -> &'static str {
+        match self {
+            SuMode::Internal => "internal, 1/sqrt(sum w)",
+            SuMode::External => "external, from weighted residuals",
+            SuMode::Max => "larger of internal and external",
+            SuMode::Unknown => "unknown, a single data set without esu's",
+        }
+    }
+}
+
+/// quantities that are averaged over the data sets
+#[derive(Clone, Copy, PartialEq)]
+pub enum Average {
+    Parameters,
+    Metric,
+    Reciprocal,
+}
+
+impl Average {
+    /// description for the output
+    pub fn describe(&self) -> &'static str {
+        match self {
+            Average::Parameters => "cell parameters",
+            Average::Metric => "metric tensor G",
+            Average::Reciprocal => "reciprocal metric tensor G*",
+        }
+    }
+}
+
+/// weighted mean with w = 1/sigma^2, its internal and external
+/// esu, and the reduced chi^2 of the values about the mean
+#[derive(Clone, Copy)]
+pub struct WMean {
+    pub mean: f64,
+    pub internal: f64,
+    pub external: f64,
+    pub chi2: f64,
+}
+
+impl WMean {
+    /// esu according to mode; -1 if unknown, but 0 for a parameter
+    /// fixed by symmetry
+    pub fn su(&self, mode: SuMode) -> f64 {
+        match mode {
+            SuMode::Internal => self.internal,
+            SuMode::External => self.external,
+            SuMode::Max => f64::max(self.internal, self.external),
+            SuMode::Unknown if self.internal == 0.0 => 0.0,
+            SuMode::Unknown => -1.0,
+        }
+    }
+}
+
+/// normalized deviations of one data set from the others
+#[derive(Clone, Copy)]
+pub struct Deviation {
+    pub z: [f64; 6],  // per cell parameter
+    pub dmetric: f64, // rms over the metric tensor components
+}
+
+impl Deviation {
+    /// largest |z| and the name of its parameter
+    pub fn zmax(&self) -> (f64, &'static str) {
+        let names = ["a", "b", "c", "alpha", "beta", "gamma"];
+        let mut worst = (0.0, names[0]);
+        for (z, name) in self.z.iter().zip(names) {
+            if z.abs() > worst.0 {
+                worst = (z.abs(), name);
+            }
+        }
+        worst
+    }
+
+    /// larger of the parameter and the metric deviations
+    pub fn score(&self) -> f64 {
+        f64::max(self.zmax().0, self.dmetric)
+    }
+}
+
+/// the data sets are in different Laue classes or lattices and the
+/// policy is SgPolicy::Fail
+#[derive(Debug)]
+pub struct InconsistentSpaceGroups;
+
+impl std::fmt::Display for InconsistentSpaceGroups {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "Inconsistent space groups, use -s majority or -s subgroup"
+        )
+    }
+}
+
+impl std::error::Error for InconsistentSpaceGroups {}
+
+/// compare the space groups of all data sets, grouped by Laue class
+/// and lattice type, and report them if they differ. Returns the
+/// space group for XSCALE.INP and which data sets to keep
+pub fn check_spacegroups(
+    out: &mut String,
+    cells: &[Cell],
+    policy: SgPolicy,
+) -> Result<(i32, Vec<bool>), InconsistentSpaceGroups> {
+    let mut groups: Vec<(Laue, char, Vec<usize>)> = Vec::new();
+    let mut unknown: Vec<usize> = Vec::new();
+    for (i, c) in cells.iter().enumerate() {
+        let Some(laue) = spacegroup::laue(c.sg) else {
+            unknown.push(i);
+            continue;
+        };
+        let lattice = spacegroup::lattice(c.sg);
+        match groups.iter_mut().find(|g| g.0 == laue && g.1 == lattice) {
+            Some(g) => g.2.push(i),
+            None => groups.push((laue, lattice, vec![i])),
+        }
+    }
+    let keep_all = vec![true; cells.len()];
+    if groups.is_empty() {
+        return Ok((-1, keep_all));
+    }
+
+    // most frequent space group within a group, the first one on ties
+    let most_frequent = |idx: &[usize]| -> i32 {
+        let mut best = (cells[idx[0]].sg, 0);
+        for &i in idx {
+            let n = idx.iter().filter(|&&j| cells[j].sg == cells[i].sg).count();
+            if n > best.1 {
+                best = (cells[i].sg, n);
+            }
+        }
+        best.0
+    };
+
+    let sg0 = cells[groups[0].2[0]].sg;
+    if groups.len() == 1 && unknown.is_empty() && groups[0].2.iter().all(|&i| cells[i].sg == sg0) {
+        return Ok((sg0, keep_all));
+    }
+
+    *out += "! Space groups of the input data sets:\n";
+    for (laue, lattice, idx) in &groups {
+        *out += &format!(
+            "!   Laue class {}, lattice {}: {} data set(s)\n",
+            laue.symbol(),
+            lattice,
+            idx.len()
+        );
+        for &i in idx {
+            let sg = cells[i].sg;
+            *out += &format!(
+                "!     {:3} {:10} {}\n",
+                sg,
+                spacegroup::symbol(sg),
+                cells[i].file
+            );
+        }
+    }
+    if !unknown.is_empty() {
+        *out += &format!("!   unknown space group: {} data set(s)\n", unknown.len());
+        for &i in &unknown {
+            *out += &format!("!         {:10} {}\n", "?", cells[i].file);
+        }
+    }
+
+    if groups.len() == 1 {
+        let sg = most_frequent(&groups[0].2);
+        *out += &format!(
+            "! Same Laue class and lattice, using SPACE_GROUP_NUMBER= {} ({})\n!\n",
+            sg,
+            spacegroup::symbol(sg)
+        );
+        return Ok((sg, keep_all));
+    }
+
+    match policy {
+        SgPolicy::Fail => Err(InconsistentSpaceGroups),
+        SgPolicy::Majority => {
+            let mut major = &groups[0];
+            for g in &groups {
+                if g.2.len() > major.2.len() {
+                    major = g;
+                }
+            }
+            let sg = most_frequent(&major.2);
+            let mut keep = vec![false; cells.len()];
+            for &i in major.2.iter().chain(unknown.iter()) {
+                keep[i] = true;
+            }
+            *out += &format!(
+                "! Using majority SPACE_GROUP_NUMBER= {} ({}), {} data set(s) excluded\n!\n",
+                sg,
+                spacegroup::symbol(sg),
+                keep.iter().filter(|k| !**k).count()
+            );
+            Ok((sg, keep))
+        }
+        SgPolicy::Subgroup => {
+            let sgs: Vec<i32> = groups
+                .iter()
+                .flat_map(|g| g.2.iter().map(|&i| cells[i].sg))
+                .collect();
+            let sg = spacegroup::common_subgroup(&sgs).unwrap_or(1);
+            *out += &format!(
+                "! Using highest common subgroup SPACE_GROUP_NUMBER= {} ({})\n!\n",
+                sg,
+                spacegroup::symbol(sg)
+            );
+            Ok((sg, keep_all))
+        }
+    }
+}
+
+/// weighted mean cell, averaged in cell parameters or in (reciprocal)
+/// metric tensor space. Also returns the statistics of the six means
+pub fn mean_cell(
+    cells: &[Cell],
+    sg: i32,
+    with_esu: bool,
+    mode: SuMode,
+    average: Average,
+) -> (Cell, [WMean; 6]) {
+    let stats = match average {
+        Average::Parameters => mean_parameters(cells, sg, with_esu),
+        Average::Metric => mean_tensor(cells, sg, with_esu, false),
+        Average::Reciprocal => mean_tensor(cells, sg, with_esu, true),
+    };
+    let [am, bm, cm, alm, bem, gam] = stats;
+    let mcell = Cell {
+        file: String::new(),
+        sg,
+        a: am.mean,
+        b: bm.mean,
+        c: cm.mean,
+        alpha: alm.mean,
+        beta: bem.mean,
+        gamma: gam.mean,
+        a_esu: am.su(mode),
+        b_esu: bm.su(mode),
+        c_esu: cm.su(mode),
+        alpha_esu: alm.su(mode),
+        beta_esu: bem.su(mode),
+        gamma_esu: gam.su(mode),
+        ..Default::default()
+    };
+    (mcell, stats)
+}
+
+// groups of cell parameters that are equal in the crystal system of
+// sg, as indices into a, b, c, alpha, beta, gamma, and the angles
+// fixed by symmetry, which are in no group. The same indices number
+// the metric tensor components g11, g22, g33, g23, g13, g12
+fn constraints(sg: i32) -> (Vec<Vec<usize>>, [Option<f64>; 3]) {
+    let system = spacegroup::laue(sg).map_or(CrystalSystem::Triclinic, |l| l.system());
+    let mut groups: Vec<Vec<usize>> = match system {
+        CrystalSystem::Tetragonal | CrystalSystem::Hexagonal => vec![vec![0, 1], vec![2]],
+        CrystalSystem::Cubic => vec![vec![0, 1, 2]],
+        _ => vec![vec![0], vec![1], vec![2]],
+    };
+    let fixed: [Option<f64>; 3] = match system {
+        CrystalSystem::Triclinic => [None, None, None],
+        CrystalSystem::Monoclinic => [Some(90.0), None, Some(90.0)],
+        CrystalSystem::Hexagonal => [Some(90.0), Some(90.0), Some(120.0)],
+        _ => [Some(90.0), Some(90.0), Some(90.0)],
+    };
+    for (i, f) in fixed.iter().enumerate() {
+        if f.is_none() {
+            groups.push(vec![3 + i]);
+        }
+    }
+    (groups, fixed)
+}
+
+// cell parameters with their esu's
+fn parameters(c: &Cell) -> [(f64, f64); 6] {
+    [
+        (c.a, c.a_esu),
+        (c.b, c.b_esu),
+        (c.c, c.c_esu),
+        (c.alpha, c.alpha_esu),
+        (c.beta, c.beta_esu),
+        (c.gamma, c.gamma_esu),
+    ]
+}
+
+// value and esu of a group of tied parameters of one data set. They
+// are one parameter of the refinement in XDS, so each data set
+// contributes their mean once, with the mean of their known esu's, or
+// 0 if none is known
+fn tied(p: &[(f64, f64); 6], group: &[usize]) -> (f64, f64) {
+    let n = group.len() as f64;
+    let value = group.iter().map(|&j| p[j].0).sum::<f64>() / n;
+    let known: Vec<f64> = group.iter().map(|&j| p[j].1).filter(|s| *s > 0.0).collect();
+    let esu = match known.len() {
+        0 => 0.0,
+        k => known.iter().sum::<f64>() / k as f64,
+    };
+    (value, esu)
+}
+
+// esu's of one parameter over all data sets. Data sets with zero esu
+// had the parameter constrained in their own space group; they get
+// the average esu of the others, or 1 if there are none
+fn fill(sigmas: &mut [f64]) {
+    let known: Vec<f64> = sigmas.iter().copied().filter(|s| *s > 0.0).collect();
+    let fill = if known.is_empty() {
+        1.0
+    } else {
+        known.iter().sum::<f64>() / known.len() as f64
+    };
+    for s in sigmas.iter_mut().filter(|s| **s <= 0.0) {
+        *s = fill;
+    }
+}
+
+// esu's of the parameter groups for each data set, all 1 without esu's
+fn group_esus(cells: &[Cell], groups: &[Vec<usize>], with_esu: bool) -> Vec<Vec<f64>> {
+    groups
+        .iter()
+        .map(|group| {
+            let mut sigmas: Vec<f64> = cells
+                .iter()
+                .map(|c| {
+                    if with_esu {
+                        tied(&parameters(c), group).1
+                    } else {
+                        1.0
+                    }
+                })
+                .collect();
+            fill(&mut sigmas);
+            sigmas
+        })
+        .collect()
+}
+
+// weighted mean of the cell parameters. Parameters that are equal in
+// the crystal system of sg are averaged together, angles fixed by
+// symmetry are set with esu 0. Without esu's, all weights are equal
+fn mean_parameters(cells: &[Cell], sg: i32, with_esu: bool) -> [WMean; 6] {
+    let (groups, fixed) = constraints(sg);
+    let esus = group_esus(cells, &groups, with_esu);
+    let stats: Vec<WMean> = groups
+        .iter()
+        .zip(&esus)
+        .map(|(group, sigmas)| {
+            let vals: Vec<f64> = cells
+                .iter()
+                .map(|c| tied(&parameters(c), group).0)
+                .collect();
+            wmean(&vals, sigmas)
+        })
+        .collect();
+    // a zero sigma marks a constraint for wmean
+    std::array::from_fn(|k| match groups.iter().position(|g| g.contains(&k)) {
+        Some(n) => stats[n],
+        None => wmean(&[fixed[k - 3].unwrap_or(90.0)], &[0.0]),
+    })
+}
+
+// weighted mean of the metric tensors G, or G* if reciprocal, by
+// generalised least squares. The components that are free in the
+// crystal system of sg, with tied ones counted once, have a full
+// covariance matrix for each data set, propagated numerically from
+// the esu's of its cell parameters; each data set is weighted with
+// the inverse of that matrix. The cell parameters follow from the
+// mean tensor, their esu's from its covariance, including the
+// correlations between the components. chi^2 is the one of the whole
+// fit, with k (N-1) degrees of freedom for k components and N data sets
+fn mean_tensor(cells: &[Cell], sg: i32, with_esu: bool, reciprocal: bool) -> [WMean; 6] {
+    let (groups, fixed) = constraints(sg);
+    let esus = group_esus(cells, &groups, with_esu);
+    let k = groups.len();
+
+    // free components of the tensor of cell parameters p
+    let components = |p: &[f64; 6]| -> Vec<f64> {
+        let c = Cell {
+            a: p[0],
+            b: p[1],
+            c: p[2],
+            alpha: p[3],
+            beta: p[4],
+            gamma: p[5],
+            ..Default::default()
+        };
+        let g = metric(&c, reciprocal);
+        groups
+            .iter()
+            .map(|group| group.iter().map(|&j| g[j]).sum::<f64>() / group.len() as f64)
+            .collect()
+    };
+
+    // components and their weight matrix for each data set
+    let mut samples: Vec<(Vec<f64>, Vec<Vec<f64>>)> = Vec::new();
+    for (i, cell) in cells.iter().enumerate() {
+        let p0 = parameters(cell).map(|p| p.0);
+        let x0 = components(&p0);
+        let mut cov = vec![vec![0.0; k]; k];
+        for (group, sigmas) in groups.iter().zip(&esus) {
+            let mut p1 = p0;
+            for &j in group {
+                p1[j] += sigmas[i];
+            }
+            let d: Vec<f64> = components(&p1)
+                .iter()
+                .zip(&x0)
+                .map(|(x, y)| x - y)
+                .collect();
+            for (row, dm) in cov.iter_mut().zip(&d) {
+                for (c, dn) in row.iter_mut().zip(&d) {
+                    *c += dm * dn;
+                }
+            }
+        }
+        samples.push((x0, inverse_or_diagonal(&cov)));
+    }
+
+    // normal equations: (sum W) x = sum W x_i
+    let mut normal = vec![vec![0.0; k]; k];
+    let mut rhs = vec![0.0; k];
+    for (x, w) in &samples {
+        for m in 0..k {
+            for n in 0..k {
+                normal[m][n] += w[m][n];
+                rhs[m] += w[m][n] * x[n];
+            }
+        }
+    }
+    let v = inverse_or_diagonal(&normal);
+    let mean: Vec<f64> = (0..k)
+        .map(|m| (0..k).map(|n| v[m][n] * rhs[n]).sum())
+        .collect();
+    let chi2 = if cells.len() < 2 {
+        0.0
+    } else {
+        let sumres: f64 = samples
+            .iter()
+            .map(|(x, w)| {
+                let r: Vec<f64> = x.iter().zip(&mean).map(|(x, m)| x - m).collect();
+                (0..k)
+                    .map(|m| (0..k).map(|n| r[m] * w[m][n] * r[n]).sum::<f64>())
+                    .sum::<f64>()
+            })
+            .sum();
+        sumres / (k * (cells.len() - 1)) as f64
+    };
+
+    // cell parameters of the free components x
+    let params = |x: &[f64]| -> [f64; 6] {
+        let mut g = [0.0; 6];
+        for (group, x) in groups.iter().zip(x) {
+            for &j in group {
+                g[j] = *x;
+            }
+        }
+        from_metric(&g, &fixed, reciprocal)
+    };
+    let p0 = params(&mean);
+
+    // derivatives of the parameters by the components, numerically
+    // with a shift of one esu, and var(p) = J V J^T
+    let jacobian: Vec<[f64; 6]> = (0..k)
+        .map(|n| {
+            let h = v[n][n].max(0.0).sqrt();
+            if h == 0.0 {
+                return [0.0; 6];
+            }
+            let mut x = mean.clone();
+            x[n] += h;
+            let p1 = params(&x);
+            std::array::from_fn(|j| (p1[j] - p0[j]) / h)
+        })
+        .collect();
+    let var: [f64; 6] = std::array::from_fn(|j| {
+        (0..k)
+            .map(|m| {
+                (0..k)
+                    .map(|n| jacobian[m][j] * v[m][n] * jacobian[n][j])
+                    .sum::<f64>()
+            })
+            .sum::<f64>()
+            .max(0.0)
+    });
+
+    std::array::from_fn(|j| {
+        let free = groups.iter().any(|g| g.contains(&j));
+        let internal = var[j].sqrt();
+        WMean {
+            mean: p0[j],
+            internal,
+            external: if cells.len() < 2 {
+                internal
+            } else {
+                internal * chi2.sqrt()
+            },
+            chi2: if free { chi2 } else { 0.0 },
+        }
+    })
+}
+
+// inverse of a symmetric positive definite matrix by Gauss-Jordan
+// elimination with partial pivoting. A singular matrix, e.g. from
+// a degenerate cell, is inverted as if it were diagonal
+fn inverse_or_diagonal(m: &[Vec<f64>]) -> Vec<Vec<f64>> {
+    let k = m.len();
+    let diagonal = || -> Vec<Vec<f64>> {
+        (0..k)
+            .map(|i| {
+                (0..k)
+                    .map(|j| match (i == j, m[i][i] > 0.0) {
+                        (true, true) => 1.0 / m[i][i],
+                        _ => 0.0,
+                    })
+                    .collect()
+            })
+            .collect()
+    };
+    let scale = (0..k).map(|i| m[i][i].abs()).fold(0.0, f64::max);
+    let mut a: Vec<Vec<f64>> = m.to_vec();
+    let mut inv: Vec<Vec<f64>> = (0..k)
+        .map(|i| (0..k).map(|j| if i == j { 1.0 } else { 0.0 }).collect())
+        .collect();
+    for col in 0..k {
+        let pivot = (col..k)
+            .max_by(|&x, &y| a[x][col].abs().total_cmp(&a[y][col].abs()))
+            .unwrap_or(col);
+        let p = a[pivot][col].abs();
+        if p.is_nan() || p <= 1.0e-12 * scale {
+            return diagonal();
+        }
+        a.swap(col, pivot);
+        inv.swap(col, pivot);
+        let d = a[col][col];
+        for j in 0..k {
+            a[col][j] /= d;
+            inv[col][j] /= d;
+        }
+        for row in 0..k {
+            if row != col {
+                let f = a[row][col];
+                for j in 0..k {
+                    a[row][j] -= f * a[col][j];
+                    inv[row][j] -= f * inv[col][j];
+                }
+            }
+        }
+    }
+    inv
+}
+
+/// which data sets have an ISa of at least min; data sets without
+/// error model are kept. Prints the data sets that are left out
+pub fn reject_low_isa(out: &mut String, cells: &[Cell], min: f64) -> Vec<bool> {
+    let keep: Vec<bool> = cells
+        .iter()
+        .map(|c| c.error_model.is_none_or(|e| e.isa >= min))
+        .collect();
+    if keep.contains(&false) {
+        *out += &format!("! Data sets with ISa below {min:.2}, left out:\n");
+        for (cell, _) in cells.iter().zip(&keep).filter(|(_, k)| !**k) {
+            let isa = cell.error_model.map_or(0.0, |e| e.isa);
+            *out += &format!("! {isa:8.2}  {}\n", cell.file);
+        }
+        *out += "!\n";
+    }
+    keep
+}
+
+/// cells with their esu's scaled by `<ISa>`/ISa, so that the weights
+/// 1/esu^2 of the mean are multiplied by `(ISa/<ISa>)^2`. `<ISa>` is the
+/// mean of the data sets with error model, the others are unchanged
+pub fn isa_weighted(cells: &[Cell]) -> Vec<Cell> {
+    let isa: Vec<f64> = cells
+        .iter()
+        .filter_map(|c| c.error_model.map(|e| e.isa))
+        .collect();
+    if isa.is_empty() {
+        return cells.to_vec();
+    }
+    let mean = isa.iter().sum::<f64>() / isa.len() as f64;
+    cells
+        .iter()
+        .map(|c| {
+            let f = c.error_model.map_or(1.0, |e| mean / e.isa);
+            // esu's of 0 (fixed) and -1 (unknown) stay as they are
+            let scale = |esu: f64| if esu > 0.0 { f * esu } else { esu };
+            Cell {
+                a_esu: scale(c.a_esu),
+                b_esu: scale(c.b_esu),
+                c_esu: scale(c.c_esu),
+                alpha_esu: scale(c.alpha_esu),
+                beta_esu: scale(c.beta_esu),
+                gamma_esu: scale(c.gamma_esu),
+                ..c.clone()
+            }
+        })
+        .collect()
+}
+
+/// iterative rejection of data sets whose cell deviates by more
+/// than nsigma from the mean of the others, one data set per
+/// cycle, as long as at least four data sets remain. Prints a report
+/// and returns which data sets are kept
+pub fn reject_outliers(out: &mut String, cells: &[Cell], nsigma: f64) -> Vec<bool> {
+    let mut active = vec![true; cells.len()];
+    loop {
+        if active.iter().filter(|a| **a).count() <= 4 {
+            break;
+        }
+        let mut worst: Option<(usize, f64)> = None;
+        for i in (0..cells.len()).filter(|&i| active[i]) {
+            let score = deviation(cells, &active, i).score();
+            if score > nsigma && worst.is_none_or(|w| score > w.1) {
+                worst = Some((i, score));
+            }
+        }
+        match worst {
+            Some((i, _)) => active[i] = false,
+            None => break,
+        }
+    }
+
+    *out += &format!("! Outlier test, leave-one-out deviations, limit {nsigma:.1} sigma:\n");
+    *out += "!   max|z| param   D(G)  data set\n";
+    for (i, cell) in cells.iter().enumerate() {
+        let dev = deviation(cells, &active, i);
+        let (z, name) = dev.zmax();
+        *out += &format!(
+            "! {:8.2} {:5} {:6.2}  {}{}\n",
+            z,
+            name,
+            dev.dmetric,
+            cell.file,
+            if active[i] { "" } else { "  <-- rejected" }
+        );
+    }
+    *out += "!\n";
+    active
+}
+
+// deviation of data set i from the mean of the other active data
+// sets, normalized by their scatter and the data set's own esu.
+// The metric tensor components of the Niggli reduced cells combine
+// all parameters independent of the setting; their esu's are
+// propagated from the cell as in mean_tensor
+fn deviation(cells: &[Cell], active: &[bool], i: usize) -> Deviation {
+    let tensor = |c: &Cell| -> [(f64, f64); 6] {
+        let (reduced, p) = niggli::reduce(c);
+        let g0 = metric(&reduced, false);
+        let (groups, _) = constraints(c.sg);
+        let p0 = parameters(c);
+        let mut var = [0.0; 6];
+        for group in &groups {
+            let (_, esu) = tied(&p0, group);
+            let mut x = p0.map(|p| p.0);
+            for &j in group {
+                x[j] += esu;
+            }
+            let shifted = Cell {
+                a: x[0],
+                b: x[1],
+                c: x[2],
+                alpha: x[3],
+                beta: x[4],
+                gamma: x[5],
+                ..c.clone()
+            };
+            let g1 = metric(&niggli::transform_cell(&shifted, &p), false);
+            for k in 0..6 {
+                var[k] += (g1[k] - g0[k]).powi(2);
+            }
+        }
+        std::array::from_fn(|k| (g0[k], var[k].sqrt()))
+    };
+    // z-scores of data set i for the six values of f
+    let zscores = |f: &dyn Fn(&Cell) -> [(f64, f64); 6]| -> [Option<f64>; 6] {
+        let others: Vec<[(f64, f64); 6]> = (0..cells.len())
+            .filter(|&j| j != i && active[j])
+            .map(|j| f(&cells[j]))
+            .collect();
+        let this = f(&cells[i]);
+        let n = others.len() as f64;
+        let mut z = [None; 6];
+        if others.len() < 2 {
+            return z;
+        }
+        for k in 0..6 {
+            let mean = others.iter().map(|o| o[k].0).sum::<f64>() / n;
+            let var = others.iter().map(|o| (o[k].0 - mean).powi(2)).sum::<f64>() / (n - 1.0);
+            let esu = this[k].1.max(0.0);
+            let denom = f64::sqrt(var * (1.0 + 1.0 / n) + esu * esu);
+            if denom > 0.0 {
+                z[k] = Some((this[k].0 - mean) / denom);
+            }
+        }
+        z
+    };
+
+    let z = zscores(&parameters).map(|z| z.unwrap_or(0.0));
+    // components that are equal in data set i, e.g. g11 = g22 of a
+    // tetragonal cell, are one and count once; those of 90 deg angles
+    // are zero up to rounding and left out
+    let g = tensor(&cells[i]);
+    let tiny = 1.0e-9 * g[..3].iter().map(|g| g.0).fold(0.0, f64::max);
+    let zg: Vec<f64> = zscores(&tensor)
+        .into_iter()
+        .enumerate()
+        .filter(|(k, _)| {
+            g[*k].0.abs() > tiny
+                && !(k / 3 * 3..*k).any(|m| (g[m].0 - g[*k].0).abs() <= 1.0e-9 * g[*k].0.abs())
+        })
+        .filter_map(|(_, z)| z)
+        .collect();
+    let dmetric = if zg.is_empty() {
+        0.0
+    } else {
+        f64::sqrt(zg.iter().map(|z| z * z).sum::<f64>() / zg.len() as f64)
+    };
+    Deviation { z, dmetric }
+}
+
+/// compute weighted mean with w = 1/sigma^2
+/// if one sigma == 0, assume this is constraint, return
+/// first value and 0
+/// internal esu: 1/sqrt(sum w)
+/// external esu: sqrt(sum w (x-mean)^2 / ((n-1) sum w))
+/// For a single value, the external esu equals the internal
+/// one and chi^2 is 0
+pub fn wmean(vals: &[f64], sigmas: &[f64]) -> WMean {
+    if sigmas[0] == 0.0 {
+        return WMean {
+            mean: vals[0],
+            internal: 0.0,
+            external: 0.0,
+            chi2: 0.0,
+        };
+    }
+    let mut mean: f64 = 0.0;
+
+    let mut sumw: f64 = 0.0;
+
+    for it in vals.iter().zip(sigmas.iter()) {
+        let (x, s) = it;
+        mean += *x / (*s * *s);
+        sumw += 1.0 / (*s * *s);
+    }
+
+    mean /= sumw;
+    let internal = 1.0 / f64::sqrt(sumw);
+
+    let n = vals.len();
+    if n < 2 {
+        return WMean {
+            mean,
+            internal,
+            external: internal,
+            chi2: 0.0,
+        };
+    }
+    let mut sumres: f64 = 0.0;
+    for it in vals.iter().zip(sigmas.iter()) {
+        let (x, s) = it;
+        sumres += (*x - mean) * (*x - mean) / (*s * *s);
+    }
+    let chi2 = sumres / (n - 1) as f64;
+
+    WMean {
+        mean,
+        internal,
+        external: internal * f64::sqrt(chi2),
+        chi2,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tetragonal(a: f64, c: f64) -> Cell {
+        Cell {
+            sg: 96,
+            a,
+            b: a,
+            c,
+            alpha: 90.0,
+            beta: 90.0,
+            gamma: 90.0,
+            a_esu: 0.021,
+            b_esu: 0.021,
+            c_esu: 0.05,
+            alpha_esu: 0.0,
+            beta_esu: 0.0,
+            gamma_esu: 0.0,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn tied_lengths_count_once() {
+        // a and b are one parameter: three data sets give
+        // 0.021/sqrt(3), not 0.021/sqrt(6)
+        let cells = [
+            tetragonal(78.14, 37.1),
+            tetragonal(78.14, 37.1),
+            tetragonal(78.14, 37.1),
+        ];
+        let (mcell, stats) = mean_cell(&cells, 96, true, SuMode::Max, Average::Parameters);
+        assert!((mcell.a_esu - 0.021 / f64::sqrt(3.0)).abs() < 1.0e-9);
+        assert_eq!(mcell.a_esu, mcell.b_esu);
+        assert_eq!(stats[0].chi2, 0.0);
+        assert_eq!(mcell.alpha_esu, 0.0);
+    }
+
+    #[test]
+    fn tied_components_count_once() {
+        let cells = [
+            tetragonal(78.14, 37.1),
+            tetragonal(78.14, 37.1),
+            tetragonal(78.14, 37.1),
+        ];
+        for average in [Average::Metric, Average::Reciprocal] {
+            let (mcell, _) = mean_cell(&cells, 96, true, SuMode::Max, average);
+            assert!((mcell.a - 78.14).abs() < 1.0e-9);
+            assert!((mcell.a_esu / (0.021 / f64::sqrt(3.0)) - 1.0).abs() < 1.0e-3);
+            assert_eq!(mcell.alpha, 90.0);
+        }
+    }
+
+    #[test]
+    fn covariance_round_trip() {
+        // one triclinic cell: its esu's propagated to G and back, with
+        // the correlations between the components
+        let cell = Cell {
+            sg: 1,
+            a: 10.0,
+            b: 12.0,
+            c: 15.0,
+            alpha: 80.0,
+            beta: 95.0,
+            gamma: 105.0,
+            a_esu: 0.002,
+            b_esu: 0.003,
+            c_esu: 0.004,
+            alpha_esu: 0.01,
+            beta_esu: 0.02,
+            gamma_esu: 0.03,
+            ..Default::default()
+        };
+        for average in [Average::Metric, Average::Reciprocal] {
+            let (mcell, _) = mean_cell(
+                std::slice::from_ref(&cell),
+                1,
+                true,
+                SuMode::Internal,
+                average,
+            );
+            let found = parameters(&mcell);
+            for (p, q) in parameters(&cell).iter().zip(found) {
+                assert!((p.0 - q.0).abs() < 1.0e-9, "{p:?} {q:?}");
+                assert!((q.1 / p.1 - 1.0).abs() < 1.0e-2, "{p:?} {q:?}");
+            }
+        }
+    }
+
+    #[test]
+    fn metric_deviation_with_esu() {
+        // only a = b deviates; g22 = g33 of the reduced cell (c, a, a)
+        // count once, c agrees, and the own esu enters as for z(a)
+        let cells: Vec<Cell> = [78.10, 78.12, 78.14, 78.16, 78.20]
+            .iter()
+            .map(|a| tetragonal(*a, 37.1))
+            .collect();
+        let dev = deviation(&cells, &[true; 5], 4);
+        let (z, name) = dev.zmax();
+        assert_eq!(name, "a");
+        assert!((dev.dmetric / (z / f64::sqrt(2.0)) - 1.0).abs() < 1.0e-2);
+    }
+
+    #[test]
+    fn single_cell_without_esu() {
+        // unit weights of a single data set give no esu; the angles
+        // fixed by symmetry keep their 0
+        let cell = Cell {
+            sg: 96,
+            a: 78.1,
+            b: 78.1,
+            c: 37.2,
+            ..Default::default()
+        };
+        for average in [Average::Parameters, Average::Metric, Average::Reciprocal] {
+            let (mcell, _) = mean_cell(
+                std::slice::from_ref(&cell),
+                96,
+                false,
+                SuMode::Unknown,
+                average,
+            );
+            assert!((mcell.a - 78.1).abs() < 1.0e-9);
+            assert_eq!([mcell.a_esu, mcell.b_esu, mcell.c_esu], [-1.0, -1.0, -1.0]);
+            assert_eq!(mcell.alpha_esu, 0.0);
+            assert_eq!(crate::su::format(mcell.a, mcell.a_esu), "78.1");
+        }
+    }
+}
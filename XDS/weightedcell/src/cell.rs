@@ -0,0 +1,322 @@
+//! Unit cells with esu's, lattice vectors and metric tensors
+
+use std::f64::consts::PI;
+use std::ops::{Add, Div, Mul, Sub};
+
+use crate::scan::Scan;
+use crate::spacegroup::{self, CrystalSystem};
+use crate::statistics::Statistics;
+
+/// file a data set was read from
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Source {
+    /// CORRECT.LP, with esu's of the cell and the number of indexed spots
+    CorrectLp,
+    /// header of XDS_ASCII.HKL, without esu's and indexed spots
+    XdsAscii,
+}
+
+impl Source {
+    /// short description for the output
+    pub fn describe(&self) -> &'static str {
+        match self {
+            Source::CorrectLp => "CORRECT.LP",
+            Source::XdsAscii => "XDS_ASCII.HKL header, no esu's",
+        }
+    }
+}
+
+/// error model of CORRECT: sigma(I)^2 = a (sigma_0(I)^2 + b I^2),
+/// with the asymptotic I/sigma ISa = 1/sqrt(a b)
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct ErrorModel {
+    pub a: f64,
+    pub b: f64,
+    pub isa: f64,
+}
+
+/// cell parameters including esu. esu's are -1 if unknown and 0 for
+/// parameters fixed by symmetry; sg is -1 if unknown
+#[derive(Clone)]
+pub struct Cell {
+    pub file: String,
+    pub source: Source,
+    pub error_model: Option<ErrorModel>,
+    pub statistics: Option<Statistics>,
+    pub scan: Scan,
+    /// problems that did not prevent reading the data set
+    pub warnings: Vec<String>,
+    pub sg: i32,
+    pub a: f64,
+    pub b: f64,
+    pub c: f64,
+    pub alpha: f64,
+    pub beta: f64,
+    pub gamma: f64,
+    pub a_esu: f64,
+    pub b_esu: f64,
+    pub c_esu: f64,
+    pub alpha_esu: f64,
+    pub beta_esu: f64,
+    pub gamma_esu: f64,
+}
+
+impl Default for Cell {
+    fn default() -> Cell {
+        Cell {
+            file: String::new(),
+            source: Source::CorrectLp,
+            error_model: None,
+            statistics: None,
+            scan: Scan::default(),
+            warnings: Vec::new(),
+            sg: -1,
+            a: 10.0,
+            b: 10.0,
+            c: 10.0,
+            alpha: 90.0,
+            beta: 90.0,
+            gamma: 90.0,
+            a_esu: -1.0,
+            b_esu: -1.0,
+            c_esu: -1.0,
+            alpha_esu: -1.0,
+            beta_esu: -1.0,
+            gamma_esu: -1.0,
+        }
+    }
+}
+
+/// 3D vectors
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Clone)]
+pub struct XYZ {
+    pub xyz: [f64; 3],
+}
+
+fn cross(x1: &XYZ, x2: &XYZ) -> XYZ {
+    let x = x1.xyz[1] * x2.xyz[2] - x1.xyz[2] * x2.xyz[1];
+    let y = x1.xyz[2] * x2.xyz[0] - x1.xyz[0] * x2.xyz[2];
+    let z = x1.xyz[0] * x2.xyz[1] - x1.xyz[1] * x2.xyz[0];
+
+    XYZ { xyz: [x, y, z] }
+}
+
+impl Mul for XYZ {
+    type Output = f64;
+    fn mul(self, other: XYZ) -> f64 {
+        self.xyz[0] * other.xyz[0] + self.xyz[1] * other.xyz[1] + self.xyz[2] * other.xyz[2]
+    }
+}
+
+impl Mul<f64> for XYZ {
+    type Output = Self;
+    fn mul(self, s: f64) -> Self {
+        Self {
+            xyz: [s * self.xyz[0], s * self.xyz[1], s * self.xyz[2]],
+        }
+    }
+}
+
+impl Mul<i32> for XYZ {
+    type Output = Self;
+    fn mul(self, si: i32) -> Self {
+        let s = si as f64;
+        Self {
+            xyz: [s * self.xyz[0], s * self.xyz[1], s * self.xyz[2]],
+        }
+    }
+}
+
+impl Div<f64> for XYZ {
+    type Output = Self;
+    fn div(self, d: f64) -> Self {
+        let s = 1.0 / d;
+        Self {
+            xyz: [s * self.xyz[0], s * self.xyz[1], s * self.xyz[2]],
+        }
+    }
+}
+
+impl Add for XYZ {
+    type Output = Self;
+    fn add(self, other: Self) -> Self {
+        Self {
+            xyz: [
+                self.xyz[0] + other.xyz[0],
+                self.xyz[1] + other.xyz[1],
+                self.xyz[2] + other.xyz[2],
+            ],
+        }
+    }
+}
+
+impl Sub for XYZ {
+    type Output = Self;
+    fn sub(self, other: Self) -> Self {
+        Self {
+            xyz: [
+                self.xyz[0] - other.xyz[0],
+                self.xyz[1] - other.xyz[1],
+                self.xyz[2] - other.xyz[2],
+            ],
+        }
+    }
+}
+
+/// volume of the cell spanned by a, b, c
+pub fn volume(a: &XYZ, b: &XYZ, c: &XYZ) -> f64 {
+    let cstar = cross(a, b);
+    cstar * c.clone()
+}
+
+/// reciprocal basis vectors a*, b*, c*
+pub fn rec_cell(a: XYZ, b: XYZ, c: XYZ) -> (XYZ, XYZ, XYZ) {
+    let vol = volume(&a, &b, &c);
+    let astar = cross(&b, &c) / vol;
+    let bstar = cross(&c, &a) / vol;
+    let cstar = cross(&a, &b) / vol;
+    (astar, bstar, cstar)
+}
+
+/// basis vectors of the cell, a along x and b in the x-y plane
+pub fn abc2vector(a: f64, b: f64, c: f64, alpha: f64, beta: f64, gamma: f64) -> (XYZ, XYZ, XYZ) {
+    let alpha = PI / 180.0 * alpha;
+    let beta = PI / 180.0 * beta;
+    let gamma = PI / 180.0 * gamma;
+    let avec = XYZ { xyz: [a, 0.0, 0.0] };
+    let bvec = XYZ {
+        xyz: [b * f64::cos(gamma), b * f64::sin(gamma), 0.0],
+    };
+    let c0 = c * f64::cos(beta);
+    let c1 = (b * c * f64::cos(alpha) - bvec.xyz[0] * c0) / bvec.xyz[1];
+    let c2 = f64::sqrt(c * c - c0 * c0 - c1 * c1);
+    let cvec = XYZ { xyz: [c0, c1, c2] };
+
+    (avec, bvec, cvec)
+}
+
+/// metric tensor components g11, g22, g33, g23, g13, g12 of the
+/// direct or the reciprocal cell
+pub fn metric(cell: &Cell, reciprocal: bool) -> [f64; 6] {
+    let (a, b, c) = abc2vector(cell.a, cell.b, cell.c, cell.alpha, cell.beta, cell.gamma);
+    let (a, b, c) = if reciprocal {
+        rec_cell(a, b, c)
+    } else {
+        (a, b, c)
+    };
+    [
+        a.clone() * a.clone(),
+        b.clone() * b.clone(),
+        c.clone() * c.clone(),
+        b.clone() * c.clone(),
+        a.clone() * c,
+        a * b,
+    ]
+}
+
+/// cell parameters from the (reciprocal) metric tensor; angles fixed
+/// by symmetry are given for the direct cell and imposed exactly
+pub fn from_metric(g: &[f64; 6], fixed: &[Option<f64>; 3], reciprocal: bool) -> [f64; 6] {
+    let todeg = 180.0 / PI;
+    let (a, b, c) = (g[0].sqrt(), g[1].sqrt(), g[2].sqrt());
+    let mut p = [
+        a,
+        b,
+        c,
+        todeg * f64::acos(g[3] / (b * c)),
+        todeg * f64::acos(g[4] / (a * c)),
+        todeg * f64::acos(g[5] / (a * b)),
+    ];
+    if reciprocal {
+        // gamma* = 180 - gamma if alpha = beta = 90
+        for (i, f) in fixed.iter().enumerate() {
+            if let Some(f) = f {
+                p[3 + i] = 180.0 - f;
+            }
+        }
+        let (a, b, c) = abc2vector(p[0], p[1], p[2], p[3], p[4], p[5]);
+        let (a, b, c) = rec_cell(a, b, c);
+        let g = [
+            a.clone() * a.clone(),
+            b.clone() * b.clone(),
+            c.clone() * c.clone(),
+            b.clone() * c.clone(),
+            a.clone() * c,
+            a * b,
+        ];
+        return from_metric(&g, fixed, false);
+    }
+    for (i, f) in fixed.iter().enumerate() {
+        if let Some(f) = f {
+            p[3 + i] = *f;
+        }
+    }
+    p
+}
+
+/// cell volume and its esu, propagated from the esu's of
+/// the cell parameters. Lengths that are equal in the crystal system
+/// of the cell are one parameter, other correlations are neglected
+pub fn cell_volume(cell: &Cell) -> (f64, f64) {
+    let torad = PI / 180.0;
+    let (ca, cb, cg) = (
+        f64::cos(torad * cell.alpha),
+        f64::cos(torad * cell.beta),
+        f64::cos(torad * cell.gamma),
+    );
+    let (sa, sb, sg) = (
+        f64::sin(torad * cell.alpha),
+        f64::sin(torad * cell.beta),
+        f64::sin(torad * cell.gamma),
+    );
+    let root = f64::sqrt(1.0 - ca * ca - cb * cb - cg * cg + 2.0 * ca * cb * cg);
+    let abc = cell.a * cell.b * cell.c;
+    let vol = abc * root;
+
+    // partial derivatives dV/dp * esu(p)
+    let da = vol / cell.a * cell.a_esu.max(0.0);
+    let db = vol / cell.b * cell.b_esu.max(0.0);
+    let dc = vol / cell.c * cell.c_esu.max(0.0);
+    let dal = abc * sa * (ca - cb * cg) / root * torad * cell.alpha_esu.max(0.0);
+    let dbe = abc * sb * (cb - ca * cg) / root * torad * cell.beta_esu.max(0.0);
+    let dga = abc * sg * (cg - ca * cb) / root * torad * cell.gamma_esu.max(0.0);
+    let lengths = match spacegroup::laue(cell.sg).map(|l| l.system()) {
+        Some(CrystalSystem::Tetragonal | CrystalSystem::Hexagonal) => (da + db).powi(2) + dc * dc,
+        Some(CrystalSystem::Cubic) => (da + db + dc).powi(2),
+        _ => da * da + db * db + dc * dc,
+    };
+    let esu = f64::sqrt(lengths + dal * dal + dbe * dbe + dga * dga);
+
+    (vol, esu)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn metric_triclinic() {
+        let cell = Cell {
+            a: 10.0,
+            b: 12.0,
+            c: 15.0,
+            alpha: 80.0,
+            beta: 95.0,
+            gamma: 105.0,
+            ..Default::default()
+        };
+        let cos = |x: f64| x.to_radians().cos();
+        let expected = [
+            100.0,
+            144.0,
+            225.0,
+            180.0 * cos(80.0),
+            150.0 * cos(95.0),
+            120.0 * cos(105.0),
+        ];
+        for (g, e) in metric(&cell, false).iter().zip(expected) {
+            assert!((g - e).abs() < 1.0e-9, "{g} != {e}");
+        }
+    }
+}
@@ -0,0 +1,142 @@
+//! Hierarchical clustering of unit cells with average linkage.
+//! Cells are Niggli reduced and compared by their G6 vectors
+//! (Andrews & Bernstein, 1988), the distance is given in percent of
+//! the mean G6 length
+
+use crate::niggli;
+use crate::Cell;
+
+/// binary tree of the clustering; nodes carry the linkage distance
+/// at which their two subtrees were joined
+pub enum Tree {
+    Leaf(usize),
+    Node(Box<Tree>, Box<Tree>, f64),
+}
+
+impl Tree {
+    fn height(&self) -> f64 {
+        match self {
+            Tree::Leaf(_) => 0.0,
+            Tree::Node(_, _, h) => *h,
+        }
+    }
+
+    fn leaves(&self) -> Vec<usize> {
+        match self {
+            Tree::Leaf(i) => vec![*i],
+            Tree::Node(l, r, _) => {
+                let mut v = l.leaves();
+                v.extend(r.leaves());
+                v
+            }
+        }
+    }
+
+    /// split into clusters joined at or below threshold
+    pub fn cut(&self, threshold: f64) -> Vec<Vec<usize>> {
+        match self {
+            Tree::Node(l, r, h) if *h > threshold => {
+                let mut c = l.cut(threshold);
+                c.extend(r.cut(threshold));
+                c
+            }
+            _ => vec![self.leaves()],
+        }
+    }
+}
+
+/// G6 vector a^2, b^2, c^2, 2bc cos(alpha), 2ac cos(beta), 2ab cos(gamma)
+pub fn g6(cell: &Cell) -> [f64; 6] {
+    let torad = std::f64::consts::PI / 180.0;
+    [
+        cell.a * cell.a,
+        cell.b * cell.b,
+        cell.c * cell.c,
+        2.0 * cell.b * cell.c * f64::cos(torad * cell.alpha),
+        2.0 * cell.a * cell.c * f64::cos(torad * cell.beta),
+        2.0 * cell.a * cell.b * f64::cos(torad * cell.gamma),
+    ]
+}
+
+/// G6 distance relative to the mean length of both vectors, in %;
+/// meaningful for reduced cells only
+pub fn distance(c1: &Cell, c2: &Cell) -> f64 {
+    let (g1, g2) = (g6(c1), g6(c2));
+    let norm = |g: &[f64; 6]| f64::sqrt(g.iter().map(|x| x * x).sum());
+    let diff: f64 = g1
+        .iter()
+        .zip(g2.iter())
+        .map(|(x, y)| (x - y) * (x - y))
+        .sum();
+    200.0 * f64::sqrt(diff) / (norm(&g1) + norm(&g2))
+}
+
+/// agglomerative clustering with average linkage
+pub fn cluster(cells: &[Cell]) -> Tree {
+    let n = cells.len();
+    let reduced: Vec<Cell> = cells.iter().map(|c| niggli::reduce(c).0).collect();
+    let mut dist = vec![vec![0.0; n]; n];
+    for i in 0..n {
+        for j in 0..i {
+            dist[i][j] = distance(&reduced[i], &reduced[j]);
+            dist[j][i] = dist[i][j];
+        }
+    }
+
+    let mut clusters: Vec<(Tree, Vec<usize>)> = (0..n).map(|i| (Tree::Leaf(i), vec![i])).collect();
+    while clusters.len() > 1 {
+        let mut best = (0, 1, f64::INFINITY);
+        for i in 0..clusters.len() {
+            for j in i + 1..clusters.len() {
+                let (mi, mj) = (&clusters[i].1, &clusters[j].1);
+                let mut d = 0.0;
+                for &p in mi {
+                    for &q in mj {
+                        d += dist[p][q];
+                    }
+                }
+                d /= (mi.len() * mj.len()) as f64;
+                if d < best.2 {
+                    best = (i, j, d);
+                }
+            }
+        }
+        let (i, j, d) = best;
+        let (tj, mj) = clusters.remove(j);
+        let (ti, mut mi) = clusters.remove(i);
+        mi.extend(mj);
+        clusters.insert(i, (Tree::Node(Box::new(ti), Box::new(tj), d), mi));
+    }
+    clusters.remove(0).0
+}
+
+/// text dendrogram as XSCALE.INP comments; leaves show their
+/// cluster number after cutting at threshold
+pub fn dendrogram(tree: &Tree, cells: &[Cell], clusters: &[Vec<usize>]) -> String {
+    fn branch(
+        out: &mut String,
+        tree: &Tree,
+        prefix: &str,
+        cells: &[Cell],
+        clusters: &[Vec<usize>],
+    ) {
+        match tree {
+            Tree::Leaf(i) => {
+                let n = clusters.iter().position(|c| c.contains(i)).unwrap_or(0) + 1;
+                *out += &format!("!{prefix}+- [{n}] {}\n", cells[*i].file);
+            }
+            Tree::Node(l, r, h) => {
+                *out += &format!("!{prefix}+- {h:.2}\n");
+                branch(out, l, &format!("{prefix}|  "), cells, clusters);
+                branch(out, r, &format!("{prefix}   "), cells, clusters);
+            }
+        }
+    }
+    let mut out = String::from("! Cell dendrogram, average linkage, G6 distance in %,\n");
+    out += &format!(
+        "! height of the tree {:.2}, [n]: cluster number\n",
+        tree.height()
+    );
+    branch(&mut out, tree, " ", cells, clusters);
+    out
+}
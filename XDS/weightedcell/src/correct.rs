@@ -0,0 +1,438 @@
+//! Parsers for the cell of a data set: CORRECT.LP of XDS or, if
+//! that is not available, the header of XDS_ASCII.HKL
+
+use std::fmt;
+use std::io::BufRead;
+use std::path::Path;
+use std::str::FromStr;
+
+use crate::cell::{Cell, ErrorModel, Source};
+use crate::pcf::Pcf;
+use crate::scan::{rd_xdsinp, Scan};
+use crate::statistics::{self, Shell, Statistics};
+
+/// reasons why a CORRECT.LP or XDS_ASCII.HKL cannot be used
+#[derive(Debug)]
+pub enum ParseError {
+    /// the file cannot be read
+    Io { file: String, error: std::io::Error },
+    /// the file lacks the header of CORRECT
+    NotCorrectLp { file: String },
+    /// the file does not start with !FORMAT=XDS_ASCII
+    NotXdsAscii { file: String },
+    /// a keyword is not followed by a valid value
+    Value {
+        file: String,
+        line: usize,
+        keyword: &'static str,
+        text: String,
+    },
+    /// a required keyword does not occur in the file
+    Missing { file: String, keyword: &'static str },
+}
+
+impl ParseError {
+    /// file the error refers to
+    pub fn file(&self) -> &str {
+        match self {
+            ParseError::Io { file, .. }
+            | ParseError::NotCorrectLp { file }
+            | ParseError::NotXdsAscii { file }
+            | ParseError::Value { file, .. }
+            | ParseError::Missing { file, .. } => file,
+        }
+    }
+
+    /// short reason, without the file name
+    pub fn reason(&self) -> String {
+        match self {
+            ParseError::Io { error, .. } => format!("cannot read file: {error}"),
+            ParseError::NotCorrectLp { .. } => String::from("not a CORRECT.LP from XDS"),
+            ParseError::NotXdsAscii { .. } => String::from("not an XDS_ASCII.HKL file"),
+            ParseError::Value {
+                line,
+                keyword,
+                text,
+                ..
+            } => format!("line {line}: invalid value '{text}' for {keyword}"),
+            ParseError::Missing { keyword, .. } => format!("{keyword} not found"),
+        }
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: {}", self.file(), self.reason())
+    }
+}
+
+impl std::error::Error for ParseError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ParseError::Io { error, .. } => Some(error),
+            _ => None,
+        }
+    }
+}
+
+// the n-th word of a line after keyword, parsed as T; also for the
+// header of XDS_ASCII.HKL
+pub(crate) fn value<T: FromStr>(
+    file: &str,
+    line: usize,
+    keyword: &'static str,
+    words: &[&str],
+    n: usize,
+) -> Result<T, ParseError> {
+    let text = words.get(n).copied().unwrap_or_default();
+    text.parse::<T>().map_err(|_| ParseError::Value {
+        file: file.to_string(),
+        line,
+        keyword,
+        text: if text.is_empty() {
+            String::from("<missing>")
+        } else {
+            text.to_string()
+        },
+    })
+}
+
+/// read the cell with its esu's, the error model, the statistics per
+/// resolution shell and the experimental details for the pcf file
+/// from CORRECT.LP
+pub fn rd_correct(filename: String) -> Result<(Cell, Pcf), ParseError> {
+    let mut mycell = Cell {
+        file: filename.clone(),
+        ..Default::default()
+    };
+    let mut mypcf = Pcf {
+        file: filename.clone(),
+        ..Default::default()
+    };
+
+    let correctlp = std::fs::read_to_string(&filename).map_err(|error| ParseError::Io {
+        file: filename.clone(),
+        error,
+    })?;
+
+    let file = filename.as_str();
+    let mut proper_correctlp: bool = false;
+    let mut has_cell = false;
+    // line of the error model; CORRECT prints it after each
+    // refinement, the last one is final
+    let mut error_model_line = 0;
+    let mut error_model_warning: Option<String> = None;
+    // the statistics table is printed more than once, the last one
+    // is final; rows that cannot be read are ignored
+    let mut in_table = false;
+    let mut shells: Vec<Shell> = Vec::new();
+    let mut total: Option<Shell> = None;
+    let mut scan = Scan::default();
+    for (n, l) in (1..).zip(correctlp.lines()) {
+        // parameter echo in the syntax of XDS.INP
+        scan.read_line(l);
+        if l.contains(" ***** CORRECT ***** (VERSION") {
+            proper_correctlp = true;
+            continue;
+        }
+        ///////////////////////////////////////////////
+        // PCF Details                               //
+        ///////////////////////////////////////////////
+        if l.contains(" X-RAY_WAVELENGTH=") {
+            let w: Vec<&str> = l.split_whitespace().collect();
+            mypcf.wavelength = value(file, n, "X-RAY_WAVELENGTH=", &w, 1)?;
+            continue;
+        }
+        if l.contains(" DETECTOR=") {
+            // the keyword may be followed by further keywords
+            let w: Vec<&str> = l.split('=').collect();
+            let detector = w[1].split_whitespace().next().unwrap_or_default();
+            mypcf.detector = detector.to_string();
+            continue;
+        }
+        if l.contains(" NX=") {
+            let w: Vec<&str> = l.split_whitespace().collect();
+            mypcf.nx = value(file, n, "NX=", &w, 1)?;
+            mypcf.ny = value(file, n, "NY=", &w, 3)?;
+            mypcf.qx = value(file, n, "QX=", &w, 5)?;
+            mypcf.qy = value(file, n, "QY=", &w, 7)?;
+            continue;
+        }
+        if l.contains(" DETECTOR_DISTANCE=") {
+            let w: Vec<&str> = l.split_whitespace().collect();
+            mypcf.distance = value(file, n, "DETECTOR_DISTANCE=", &w, 1)?;
+            continue;
+        }
+        if l.contains(" INDEXED SPOTS") {
+            let w: Vec<&str> = l.split_whitespace().collect();
+            mypcf.num_refl = value(file, n, "INDEXED SPOTS", &w, 7)?;
+            continue;
+        }
+
+        ///////////////////////////////////////////////
+        // XSCALE.INP Details                        //
+        ///////////////////////////////////////////////
+        if l.contains(" SPACE GROUP NUMBER ") {
+            let w: Vec<&str> = l.split_whitespace().collect();
+            mycell.sg = value(file, n, "SPACE GROUP NUMBER", &w, 3)?;
+            continue;
+            // next line should be cell and ESDs
+        }
+        if l.contains(" UNIT CELL PARAMETERS ") {
+            let w: Vec<&str> = l.split_whitespace().collect();
+            let kw = "UNIT CELL PARAMETERS";
+            mycell.a = value(file, n, kw, &w, 3)?;
+            mycell.b = value(file, n, kw, &w, 4)?;
+            mycell.c = value(file, n, kw, &w, 5)?;
+            mycell.alpha = value(file, n, kw, &w, 6)?;
+            mycell.beta = value(file, n, kw, &w, 7)?;
+            mycell.gamma = value(file, n, kw, &w, 8)?;
+            has_cell = true;
+            continue;
+        }
+        if l.contains(statistics::TABLE) {
+            in_table = true;
+            shells.clear();
+            total = None;
+            continue;
+        }
+        if in_table {
+            match statistics::shell(l) {
+                Some(s) if s.dmin == 0.0 => {
+                    total = Some(s);
+                    in_table = false;
+                }
+                Some(s) => shells.push(s),
+                None => (),
+            }
+            continue;
+        }
+        if l.split_whitespace().eq(["a", "b", "ISa"]) {
+            error_model_line = n + 1;
+            continue;
+        }
+        if n == error_model_line {
+            // an error model that cannot be read, e.g. with ISa
+            // overflowing to *****, is left out with a warning
+            let w: Vec<&str> = l.split_whitespace().collect();
+            let model = || -> Result<ErrorModel, ParseError> {
+                Ok(ErrorModel {
+                    a: value(file, n, "a", &w, 0)?,
+                    b: value(file, n, "b", &w, 1)?,
+                    isa: value(file, n, "ISa", &w, 2)?,
+                })
+            };
+            (mycell.error_model, error_model_warning) = match model() {
+                Ok(e) => (Some(e), None),
+                Err(e) => (None, Some(format!("{e}, error model ignored"))),
+            };
+            continue;
+        }
+        if l.contains(" E.S.D. OF CELL PARAMETERS") {
+            let w: Vec<&str> = l.split_whitespace().collect();
+            if w.len() == 5 {
+                mycell.a_esu = -1.0;
+                mycell.b_esu = -1.0;
+                mycell.c_esu = -1.0;
+                mycell.alpha_esu = -1.0;
+                mycell.beta_esu = -1.0;
+                mycell.gamma_esu = -1.0;
+            } else {
+                let kw = "E.S.D. OF CELL PARAMETERS";
+                mycell.a_esu = value(file, n, kw, &w, 4)?;
+                mycell.b_esu = value(file, n, kw, &w, 5)?;
+                mycell.c_esu = value(file, n, kw, &w, 6)?;
+                mycell.alpha_esu = value(file, n, kw, &w, 7)?;
+                mycell.beta_esu = value(file, n, kw, &w, 8)?;
+                mycell.gamma_esu = value(file, n, kw, &w, 9)?;
+            }
+            continue;
+        }
+    }
+    if !proper_correctlp {
+        return Err(ParseError::NotCorrectLp {
+            file: filename.clone(),
+        });
+    }
+    if !has_cell {
+        return Err(ParseError::Missing {
+            file: filename.clone(),
+            keyword: "UNIT CELL PARAMETERS",
+        });
+    }
+    mycell.statistics = total.map(|total| Statistics { shells, total });
+    mycell.scan = scan;
+    mycell.warnings.extend(error_model_warning);
+    mypcf.scan = scan;
+    // update mypcf with cell information
+    mypcf.cellabc = (
+        mycell.a,
+        mycell.b,
+        mycell.c,
+        mycell.alpha,
+        mycell.beta,
+        mycell.gamma,
+    );
+    mypcf.cellesd = (
+        mycell.a_esu,
+        mycell.b_esu,
+        mycell.c_esu,
+        mycell.alpha_esu,
+        mycell.beta_esu,
+        mycell.gamma_esu,
+    );
+    Ok((mycell, mypcf))
+}
+
+/// read a data set given as CORRECT.LP, XDS_ASCII.HKL or a directory.
+/// A directory is read from its CORRECT.LP, or from the header of
+/// XDS_ASCII.HKL if there is no CORRECT.LP; files are recognised by
+/// their first line. XDS.INP next to the file completes the scan
+pub fn rd_dataset(filename: String) -> Result<(Cell, Pcf), ParseError> {
+    let (mut cell, mut pcf) = if Path::new(&filename).is_dir() {
+        let correctlp = filename.clone() + "/CORRECT.LP";
+        let xdsascii = filename + "/XDS_ASCII.HKL";
+        if !Path::new(&correctlp).exists() && Path::new(&xdsascii).exists() {
+            rd_xdsascii(xdsascii)?
+        } else {
+            rd_correct(correctlp)?
+        }
+    } else {
+        let is_xdsascii = std::fs::File::open(&filename)
+            .ok()
+            .and_then(|f| std::io::BufReader::new(f).lines().next())
+            .and_then(|l| l.ok())
+            .is_some_and(|l| l.starts_with("!FORMAT=XDS_ASCII"));
+        if is_xdsascii {
+            rd_xdsascii(filename)?
+        } else {
+            rd_correct(filename)?
+        }
+    };
+    // items of the scan that are missing are taken from XDS.INP in
+    // the same directory, if there is one
+    let xdsinp = Path::new(&pcf.file).with_file_name("XDS.INP");
+    if let Ok(scan) = rd_xdsinp(&xdsinp.to_string_lossy()) {
+        pcf.scan = pcf.scan.or(scan);
+        cell.scan = pcf.scan;
+    }
+    Ok((cell, pcf))
+}
+
+// the words following keyword in a header record of XDS_ASCII.HKL,
+// e.g. ["2463"] for "NX=" in "!NX=  2463  NY=  2527 ..."
+fn after<'a>(l: &'a str, keyword: &str) -> Vec<&'a str> {
+    match l.find(keyword) {
+        Some(i) => l[i + keyword.len()..].split_whitespace().collect(),
+        None => Vec::new(),
+    }
+}
+
+/// read the cell and the experimental details for the pcf file from
+/// the header of XDS_ASCII.HKL. The header has no esu's of the cell
+/// and no number of indexed spots; these remain unknown
+pub fn rd_xdsascii(filename: String) -> Result<(Cell, Pcf), ParseError> {
+    let mut mycell = Cell {
+        file: filename.clone(),
+        source: Source::XdsAscii,
+        ..Default::default()
+    };
+    let mut mypcf = Pcf {
+        file: filename.clone(),
+        source: Source::XdsAscii,
+        ..Default::default()
+    };
+
+    let io_error = |error| ParseError::Io {
+        file: filename.clone(),
+        error,
+    };
+    let reader = std::io::BufReader::new(std::fs::File::open(&filename).map_err(io_error)?);
+
+    let file = filename.as_str();
+    let mut has_cell = false;
+    let mut scan = Scan::default();
+    // only the header is read, the reflections may be many
+    for (n, l) in (1..).zip(reader.lines()) {
+        let l = l.map_err(io_error)?;
+        if n == 1 {
+            if !l.starts_with("!FORMAT=XDS_ASCII") {
+                return Err(ParseError::NotXdsAscii {
+                    file: filename.clone(),
+                });
+            }
+            // FRIEDEL'S_LAW= is on this line
+            scan.read_line(&l[1..]);
+            continue;
+        }
+        if !l.starts_with('!') || l.starts_with("!END_OF_HEADER") {
+            break;
+        }
+        // header records are keywords of XDS.INP after '!'
+        scan.read_line(&l[1..]);
+        ///////////////////////////////////////////////
+        // PCF Details                               //
+        ///////////////////////////////////////////////
+        if l.starts_with("!X-RAY_WAVELENGTH=") {
+            let w = after(&l, "=");
+            mypcf.wavelength = value(file, n, "X-RAY_WAVELENGTH=", &w, 0)?;
+            continue;
+        }
+        if l.starts_with("!DETECTOR=") {
+            let detector = after(&l, "=").first().copied().unwrap_or_default();
+            mypcf.detector = detector.to_string();
+            continue;
+        }
+        if l.starts_with("!NX=") {
+            mypcf.nx = value(file, n, "NX=", &after(&l, "NX="), 0)?;
+            mypcf.ny = value(file, n, "NY=", &after(&l, "NY="), 0)?;
+            mypcf.qx = value(file, n, "QX=", &after(&l, "QX="), 0)?;
+            mypcf.qy = value(file, n, "QY=", &after(&l, "QY="), 0)?;
+            continue;
+        }
+        if l.starts_with("!DETECTOR_DISTANCE=") {
+            let w = after(&l, "=");
+            mypcf.distance = value(file, n, "DETECTOR_DISTANCE=", &w, 0)?;
+            continue;
+        }
+
+        ///////////////////////////////////////////////
+        // XSCALE.INP Details                        //
+        ///////////////////////////////////////////////
+        if l.starts_with("!SPACE_GROUP_NUMBER=") {
+            let w = after(&l, "=");
+            mycell.sg = value(file, n, "SPACE_GROUP_NUMBER=", &w, 0)?;
+            continue;
+        }
+        if l.starts_with("!UNIT_CELL_CONSTANTS=") {
+            let w = after(&l, "=");
+            let kw = "UNIT_CELL_CONSTANTS=";
+            mycell.a = value(file, n, kw, &w, 0)?;
+            mycell.b = value(file, n, kw, &w, 1)?;
+            mycell.c = value(file, n, kw, &w, 2)?;
+            mycell.alpha = value(file, n, kw, &w, 3)?;
+            mycell.beta = value(file, n, kw, &w, 4)?;
+            mycell.gamma = value(file, n, kw, &w, 5)?;
+            has_cell = true;
+            continue;
+        }
+    }
+    if !has_cell {
+        return Err(ParseError::Missing {
+            file: filename.clone(),
+            keyword: "UNIT_CELL_CONSTANTS=",
+        });
+    }
+    mypcf.cellabc = (
+        mycell.a,
+        mycell.b,
+        mycell.c,
+        mycell.alpha,
+        mycell.beta,
+        mycell.gamma,
+    );
+    mypcf.cellesd = (-1.0, -1.0, -1.0, -1.0, -1.0, -1.0);
+    mycell.scan = scan;
+    mypcf.scan = scan;
+    Ok((mycell, mypcf))
+}
@@ -0,0 +1,456 @@
+//! Reflection data from XDS_ASCII.HKL. The columns of the data records
+//! are taken from the !ITEM_ records of the header, so merged and
+//! unmerged files from CORRECT and XSCALE are read alike. Files are
+//! streamed line by line through one buffer, so their size does not
+//! matter
+
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::cell::{metric, Cell};
+use crate::correct::{value, ParseError};
+
+/// one data record of XDS_ASCII.HKL; items that are not in the file
+/// are None
+#[derive(Clone, Debug)]
+pub struct Reflection {
+    pub hkl: [i32; 3],
+    pub iobs: f64,
+    /// negative for misfits
+    pub sigma: f64,
+    pub xd: Option<f64>,
+    pub yd: Option<f64>,
+    pub zd: Option<f64>,
+    pub rlp: Option<f64>,
+    pub peak: Option<f64>,
+    pub corr: Option<f64>,
+    pub psi: Option<f64>,
+    /// input file number in the output of XSCALE
+    pub iset: Option<i32>,
+}
+
+// 0-based columns of the items
+#[derive(Clone, Debug, Default)]
+struct Columns {
+    h: Option<usize>,
+    k: Option<usize>,
+    l: Option<usize>,
+    iobs: Option<usize>,
+    sigma: Option<usize>,
+    xd: Option<usize>,
+    yd: Option<usize>,
+    zd: Option<usize>,
+    rlp: Option<usize>,
+    peak: Option<usize>,
+    corr: Option<usize>,
+    psi: Option<usize>,
+    iset: Option<usize>,
+}
+
+/// header records of XDS_ASCII.HKL that describe the data records
+#[derive(Clone, Debug, Default)]
+pub struct Header {
+    /// MERGE=TRUE on the !FORMAT= record
+    pub merged: bool,
+    /// FRIEDEL'S_LAW=TRUE on the !FORMAT= record
+    pub friedels_law: bool,
+    /// number of items in each data record
+    pub items: usize,
+    columns: Columns,
+}
+
+/// reader for the data records of XDS_ASCII.HKL, one at a time. The
+/// header is read on opening; blank lines and comments are skipped and
+/// the data end with !END_OF_DATA
+pub struct Reflections {
+    file: String,
+    pub header: Header,
+    reader: BufReader<File>,
+    // the current line, reused for all lines
+    buf: String,
+    line: usize,
+    done: bool,
+}
+
+impl Reflections {
+    /// open filename and read its header
+    pub fn open(filename: &str) -> Result<Reflections, ParseError> {
+        let file = filename.to_string();
+        let io_error = |error| ParseError::Io {
+            file: file.clone(),
+            error,
+        };
+        let mut reader = BufReader::new(File::open(filename).map_err(io_error)?);
+        let mut header = Header::default();
+        let mut n = 0;
+        let mut complete = false;
+        let mut buf = String::new();
+        loop {
+            buf.clear();
+            if reader.read_line(&mut buf).map_err(io_error)? == 0 {
+                break;
+            }
+            let l = buf.trim_end();
+            n += 1;
+            if n == 1 {
+                if !l.starts_with("!FORMAT=XDS_ASCII") {
+                    return Err(ParseError::NotXdsAscii { file });
+                }
+                header.merged = l.contains("MERGE=TRUE");
+                header.friedels_law = l.contains("FRIEDEL'S_LAW=TRUE");
+                continue;
+            }
+            if l.starts_with("!END_OF_HEADER") {
+                complete = true;
+                break;
+            }
+            if let Some(s) = l.strip_prefix("!NUMBER_OF_ITEMS_IN_EACH_DATA_RECORD=") {
+                let kw = "NUMBER_OF_ITEMS_IN_EACH_DATA_RECORD=";
+                let w: Vec<&str> = s.split_whitespace().collect();
+                header.items = value(&file, n, kw, &w, 0)?;
+                continue;
+            }
+            // e.g. !ITEM_SIGMA(IOBS)=5
+            if let Some((name, column)) = l.strip_prefix("!ITEM_").and_then(|s| s.split_once('=')) {
+                let w: Vec<&str> = column.split_whitespace().collect();
+                let column = Some(value::<usize>(&file, n, "ITEM_", &w, 0)?.max(1) - 1);
+                let c = &mut header.columns;
+                match name {
+                    "H" => c.h = column,
+                    "K" => c.k = column,
+                    "L" => c.l = column,
+                    "IOBS" => c.iobs = column,
+                    "SIGMA(IOBS)" => c.sigma = column,
+                    "XD" => c.xd = column,
+                    "YD" => c.yd = column,
+                    "ZD" => c.zd = column,
+                    "RLP" => c.rlp = column,
+                    "PEAK" => c.peak = column,
+                    "CORR" => c.corr = column,
+                    "PSI" => c.psi = column,
+                    "ISET" => c.iset = column,
+                    _ => {}
+                }
+            }
+        }
+        if !complete {
+            return Err(ParseError::Missing {
+                file,
+                keyword: "!END_OF_HEADER",
+            });
+        }
+        let c = &header.columns;
+        for (column, keyword) in [
+            (c.h, "!ITEM_H="),
+            (c.k, "!ITEM_K="),
+            (c.l, "!ITEM_L="),
+            (c.iobs, "!ITEM_IOBS="),
+            (c.sigma, "!ITEM_SIGMA(IOBS)="),
+        ] {
+            if column.is_none() {
+                return Err(ParseError::Missing { file, keyword });
+            }
+        }
+        Ok(Reflections {
+            file,
+            header,
+            reader,
+            buf,
+            line: n,
+            done: false,
+        })
+    }
+
+    // read the next data record into buf; false at the end of the data
+    fn next_record(&mut self) -> Result<bool, ParseError> {
+        while !self.done {
+            self.buf.clear();
+            let bytes = self.reader.read_line(&mut self.buf).map_err(|error| {
+                self.done = true;
+                ParseError::Io {
+                    file: self.file.clone(),
+                    error,
+                }
+            })?;
+            if bytes == 0 {
+                break;
+            }
+            self.line += 1;
+            if self.buf.starts_with("!END_OF_DATA") {
+                self.done = true;
+            } else if !self.buf.starts_with('!') && !self.buf.trim().is_empty() {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+
+    // the indices of the record in buf
+    fn indices(&self) -> Result<[i32; 3], ParseError> {
+        let c = &self.header.columns;
+        let mut hkl = [None; 3];
+        let last = [c.h, c.k, c.l].into_iter().flatten().max().unwrap_or(0);
+        for (i, w) in self.buf.split_whitespace().enumerate().take(last + 1) {
+            for (x, column) in hkl.iter_mut().zip([c.h, c.k, c.l]) {
+                if column == Some(i) {
+                    *x = w.parse::<i32>().ok();
+                }
+            }
+        }
+        match hkl {
+            [Some(h), Some(k), Some(l)] => Ok([h, k, l]),
+            _ => Err(ParseError::Value {
+                file: self.file.clone(),
+                line: self.line,
+                keyword: "data record",
+                text: self.buf.trim().to_string(),
+            }),
+        }
+    }
+
+    /// only the indices of the records, without the cost of parsing
+    /// the other items
+    pub fn hkl(mut self) -> impl Iterator<Item = Result<[i32; 3], ParseError>> {
+        std::iter::from_fn(move || match self.next_record() {
+            Ok(true) => Some(self.indices()),
+            Ok(false) => None,
+            Err(e) => Some(Err(e)),
+        })
+    }
+
+    // the data record in line l
+    fn record(&self, l: &str) -> Result<Reflection, ParseError> {
+        let w: Vec<&str> = l.split_whitespace().collect();
+        let c = &self.header.columns;
+        let error = || ParseError::Value {
+            file: self.file.clone(),
+            line: self.line,
+            keyword: "data record",
+            text: l.trim().to_string(),
+        };
+        if w.len() < self.header.items {
+            return Err(error());
+        }
+        let required = |column: Option<usize>| -> Result<f64, ParseError> {
+            column
+                .and_then(|i| w.get(i))
+                .and_then(|x| x.parse::<f64>().ok())
+                .ok_or_else(error)
+        };
+        let optional = |column: Option<usize>| -> Result<Option<f64>, ParseError> {
+            match column {
+                Some(i) => w
+                    .get(i)
+                    .and_then(|x| x.parse().ok())
+                    .map(Some)
+                    .ok_or_else(error),
+                None => Ok(None),
+            }
+        };
+        let index = |column: Option<usize>| -> Result<i32, ParseError> {
+            column
+                .and_then(|i| w.get(i))
+                .and_then(|x| x.parse::<i32>().ok())
+                .ok_or_else(error)
+        };
+        Ok(Reflection {
+            hkl: [index(c.h)?, index(c.k)?, index(c.l)?],
+            iobs: required(c.iobs)?,
+            sigma: required(c.sigma)?,
+            xd: optional(c.xd)?,
+            yd: optional(c.yd)?,
+            zd: optional(c.zd)?,
+            rlp: optional(c.rlp)?,
+            peak: optional(c.peak)?,
+            corr: optional(c.corr)?,
+            psi: optional(c.psi)?,
+            iset: match c.iset {
+                Some(_) => Some(index(c.iset)?),
+                None => None,
+            },
+        })
+    }
+}
+
+impl Iterator for Reflections {
+    type Item = Result<Reflection, ParseError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self.next_record() {
+            Ok(true) => Some(self.record(&self.buf)),
+            Ok(false) => None,
+            Err(e) => Some(Err(e)),
+        }
+    }
+}
+
+/// extract dstarmin and dstarmax from XDS_ASCII.HKL for each of
+/// cells, in one pass over the file; d*^2 is evaluated with the
+/// reciprocal metric tensor of the cell. A file without data records
+/// has no range
+pub fn resolution_range(xdsascii: &str, cells: &[&Cell]) -> Result<Vec<(f64, f64)>, ParseError> {
+    let metrics: Vec<[f64; 6]> = cells.iter().map(|c| metric(c, true)).collect();
+    let mut ranges = vec![(f64::INFINITY, -f64::INFINITY); cells.len()];
+    let mut empty = true;
+
+    for hkl in Reflections::open(xdsascii)?.hkl() {
+        let [h, k, l] = hkl?.map(f64::from);
+        empty = false;
+        for ([g11, g22, g33, g23, g13, g12], (dstarmin, dstarmax)) in
+            metrics.iter().zip(ranges.iter_mut())
+        {
+            let dstar = h * h * g11
+                + k * k * g22
+                + l * l * g33
+                + 2.0 * (k * l * g23 + h * l * g13 + h * k * g12);
+            *dstarmin = dstarmin.min(dstar);
+            *dstarmax = dstarmax.max(dstar);
+        }
+    }
+    if empty {
+        return Err(ParseError::Missing {
+            file: xdsascii.to_string(),
+            keyword: "data record",
+        });
+    }
+
+    Ok(ranges
+        .into_iter()
+        .map(|(dstarmin, dstarmax)| (f64::sqrt(dstarmin), f64::sqrt(dstarmax)))
+        .collect())
+}
+
+/// resolution_range for several files at once, each with its cells.
+/// The files are shared out to as many threads as there are cores
+#[allow(clippy::type_complexity)]
+pub fn resolution_ranges(jobs: &[(&str, Vec<&Cell>)]) -> Vec<Result<Vec<(f64, f64)>, ParseError>> {
+    let threads = std::thread::available_parallelism()
+        .map_or(1, |n| n.get())
+        .min(jobs.len());
+    let next = AtomicUsize::new(0);
+    let mut results: Vec<Option<Result<Vec<(f64, f64)>, ParseError>>> = Vec::new();
+    results.resize_with(jobs.len(), || None);
+    std::thread::scope(|scope| {
+        let workers: Vec<_> = (0..threads)
+            .map(|_| {
+                scope.spawn(|| {
+                    let mut done = Vec::new();
+                    loop {
+                        let i = next.fetch_add(1, Ordering::Relaxed);
+                        let Some((file, cells)) = jobs.get(i) else {
+                            break done;
+                        };
+                        done.push((i, resolution_range(file, cells)));
+                    }
+                })
+            })
+            .collect();
+        for worker in workers {
+            for (i, r) in worker.join().expect("resolution range thread failed") {
+                results[i] = Some(r);
+            }
+        }
+    });
+    results.into_iter().flatten().collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // content written to a file of the temporary directory
+    fn file(name: &str, content: &str) -> String {
+        let path = std::env::temp_dir().join(format!("{}_{name}", std::process::id()));
+        std::fs::write(&path, content).unwrap();
+        path.to_string_lossy().into_owned()
+    }
+
+    #[test]
+    fn unmerged_with_iset() {
+        let f = file(
+            "unmerged.HKL",
+            "!FORMAT=XDS_ASCII    MERGE=FALSE    FRIEDEL'S_LAW=TRUE
+!NUMBER_OF_ITEMS_IN_EACH_DATA_RECORD=9
+!ITEM_H=1
+!ITEM_K=2
+!ITEM_L=3
+!ITEM_IOBS=4
+!ITEM_SIGMA(IOBS)=5
+!ITEM_XD=6
+!ITEM_YD=7
+!ITEM_ZD=8
+!ITEM_ISET=9
+!END_OF_HEADER
+     1     2     3  1.000E+02  5.000E+00  100.0  200.0   10.0  1
+
+    -1     0     4  2.000E+01 -1.000E+00  110.0  210.0   11.0  2
+!END_OF_DATA
+     9     9     9  1.000E+00  1.000E+00    1.0    1.0    1.0  1
+",
+        );
+        let reflections = Reflections::open(&f).unwrap();
+        assert!(!reflections.header.merged);
+        assert!(reflections.header.friedels_law);
+        assert_eq!(reflections.header.items, 9);
+        let r: Vec<Reflection> = reflections.map(|r| r.unwrap()).collect();
+        assert_eq!(r.len(), 2);
+        assert_eq!(r[0].hkl, [1, 2, 3]);
+        assert_eq!(r[0].iobs, 100.0);
+        assert_eq!(r[0].xd, Some(100.0));
+        assert_eq!(r[0].rlp, None);
+        assert_eq!(r[0].iset, Some(1));
+        assert_eq!(r[1].sigma, -1.0);
+        assert_eq!(r[1].iset, Some(2));
+        let hkl: Vec<[i32; 3]> = Reflections::open(&f)
+            .unwrap()
+            .hkl()
+            .map(|h| h.unwrap())
+            .collect();
+        assert_eq!(hkl, [[1, 2, 3], [-1, 0, 4]]);
+        std::fs::remove_file(f).unwrap();
+    }
+
+    #[test]
+    fn merged() {
+        let f = file(
+            "merged.HKL",
+            "!FORMAT=XDS_ASCII    MERGE=TRUE    FRIEDEL'S_LAW=FALSE
+!NUMBER_OF_ITEMS_IN_EACH_DATA_RECORD=5
+!ITEM_H=1
+!ITEM_K=2
+!ITEM_L=3
+!ITEM_IOBS=4
+!ITEM_SIGMA(IOBS)=5
+!END_OF_HEADER
+     0     0     2  3.000E+03  4.000E+01
+!END_OF_DATA
+",
+        );
+        let reflections = Reflections::open(&f).unwrap();
+        assert!(reflections.header.merged);
+        assert!(!reflections.header.friedels_law);
+        let r: Vec<Reflection> = reflections.map(|r| r.unwrap()).collect();
+        assert_eq!(r.len(), 1);
+        assert_eq!(r[0].hkl, [0, 0, 2]);
+        assert_eq!(r[0].sigma, 40.0);
+        assert_eq!((r[0].xd, r[0].iset), (None, None));
+        std::fs::remove_file(f).unwrap();
+    }
+
+    #[test]
+    fn invalid_item() {
+        let f = file(
+            "invalid.HKL",
+            "!FORMAT=XDS_ASCII    MERGE=TRUE
+!NUMBER_OF_ITEMS_IN_EACH_DATA_RECORD=
+!END_OF_HEADER
+",
+        );
+        let e = Reflections::open(&f).err().unwrap();
+        assert!(e.to_string().ends_with(
+            "line 2: invalid value '<missing>' for NUMBER_OF_ITEMS_IN_EACH_DATA_RECORD="
+        ));
+        std::fs::remove_file(f).unwrap();
+    }
+}
@@ -0,0 +1,27 @@
+//! Weighted mean unit cells from XDS data sets.
+//!
+//! Reads the refined cells from CORRECT.LP, brings them onto a common
+//! setting, averages them with their esu's and writes XSCALE.INP and
+//! CIF items for the pcf file. The binary `weightedcell` is a thin
+//! wrapper around this library.
+
+pub mod average;
+pub mod cell;
+pub mod cluster;
+pub mod correct;
+pub mod hkl;
+pub mod niggli;
+pub mod pcf;
+pub mod reindex;
+pub mod scan;
+pub mod spacegroup;
+pub mod statistics;
+pub mod su;
+pub mod xscale;
+
+pub use average::{mean_cell, wmean, Average, SgPolicy, SuMode, WMean};
+pub use cell::{abc2vector, rec_cell, Cell, ErrorModel, XYZ};
+pub use correct::{rd_correct, rd_dataset, rd_xdsascii, ParseError};
+pub use hkl::{Reflection, Reflections};
+pub use pcf::{write_pcf, Pcf};
+pub use xscale::xscaleinp;
@@ -1,381 +1,546 @@
-use std::f32::consts::PI;
-use std::ops::{Add, Div, Mul, Sub};
+use std::env;
 use std::process;
 
 use chrono::{TimeZone, Utc};
-use std::env;
+
+use weightedcell::average::{
+    check_spacegroups, isa_weighted, mean_cell, reject_low_isa, reject_outliers,
+};
+use weightedcell::correct::ParseError;
+use weightedcell::niggli::Matrix;
+use weightedcell::pcf::{common_radiation, pcf_block, ThetaCell};
+use weightedcell::reindex::{self, Reindexing};
+use weightedcell::statistics::Criterion;
+use weightedcell::xscale::{xscaleinp, Input};
+use weightedcell::{cluster, rd_dataset, Average, Cell, Pcf, SgPolicy, SuMode};
 
 const PCFFILE: &str = "weightedcell.pcf";
 
-// cell parameters including esu
+// options from the command line
 #[derive(Clone)]
-struct Cell {
-    file: String,
-    sg: i32,
-    a: f32,
-    b: f32,
-    c: f32,
-    alpha: f32,
-    beta: f32,
-    gamma: f32,
-    a_esu: f32,
-    b_esu: f32,
-    c_esu: f32,
-    alpha_esu: f32,
-    beta_esu: f32,
-    gamma_esu: f32,
+struct Options {
+    pcf: bool,
+    hklout: String,
+    pcfout: String,
+    dataname: String,
+    sg_policy: SgPolicy,
+    su_mode: SuMode,
+    average: Average,
+    theta_cell: ThetaCell,
+    outlier_sigma: Option<f64>,
+    keep_outliers: bool,
+    min_isa: Option<f64>,
+    isa_weight: bool,
+    cutoff: Option<Criterion>,
+    reindex: bool,
+    cluster_threshold: Option<f64>,
 }
 
-impl Default for Cell {
-    fn default() -> Cell {
-        Cell {
-            file: String::new(),
-            sg: -1,
-            a: 10.0,
-            b: 10.0,
-            c: 10.0,
-            alpha: 90.0,
-            beta: 90.0,
-            gamma: 90.0,
-            a_esu: -1.0,
-            b_esu: -1.0,
-            c_esu: -1.0,
-            alpha_esu: -1.0,
-            beta_esu: -1.0,
-            gamma_esu: -1.0,
+// workflow:
+// - read CORRECT.LP (or the header of XDS_ASCII.HKL) and
+//   accumulate into cells_*
+// - optionally split into clusters of isomorphous cells
+// - check space groups and reject outliers
+// - compute weighted cell and esds
+// - compute reciprocal cell
+// - read XDS_ASCII.HKL to compute dmin and dmax from
+//   weighted cell
+// - output XSCALE.INP
+// - write my.pcf, or base.HKL and base.pcf with '-r base'
+fn main() {
+    let mut args = env::args().skip(1);
+    // let has_esds = false;
+    let mut all_cells: Vec<Cell> = Vec::new();
+    let mut all_pcfs: Vec<Pcf> = Vec::new();
+    let mut pcf_switch: bool = false;
+    let mut base: Option<String> = None;
+    let mut sg_policy = SgPolicy::Fail;
+    let mut su_mode = SuMode::Max;
+    let mut average = Average::Parameters;
+    let mut theta_cell = ThetaCell::Own;
+    let mut outlier_sigma: Option<f64> = None;
+    let mut keep_outliers = false;
+    let mut min_isa: Option<f64> = None;
+    let mut isa_weight = false;
+    let mut cutoff: Option<Criterion> = None;
+    let mut reindex = true;
+    let mut abort = false;
+    let mut cluster_threshold: Option<f64> = None;
+    let mut filenames: Vec<String> = Vec::new();
+
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-h" => {
+                usage();
+                process::exit(1);
+            }
+            "-w" => pcf_switch = true,
+            "-r" => match args.next() {
+                Some(b) => base = Some(b),
+                None => {
+                    usage();
+                    println!("\n---> Option -r requires a base name <---");
+                    process::exit(1);
+                }
+            },
+            "-s" => match args.next().as_deref() {
+                Some("fail") => sg_policy = SgPolicy::Fail,
+                Some("majority") => sg_policy = SgPolicy::Majority,
+                Some("subgroup") => sg_policy = SgPolicy::Subgroup,
+                _ => {
+                    usage();
+                    println!("\n---> Option -s requires fail, majority, or subgroup <---");
+                    process::exit(1);
+                }
+            },
+            "-e" => match args.next().as_deref() {
+                Some("internal") => su_mode = SuMode::Internal,
+                Some("external") => su_mode = SuMode::External,
+                Some("max") => su_mode = SuMode::Max,
+                _ => {
+                    usage();
+                    println!("\n---> Option -e requires internal, external, or max <---");
+                    process::exit(1);
+                }
+            },
+            "-m" => match args.next().as_deref() {
+                Some("param") => average = Average::Parameters,
+                Some("metric") => average = Average::Metric,
+                Some("reciprocal") => average = Average::Reciprocal,
+                _ => {
+                    usage();
+                    println!("\n---> Option -m requires param, metric, or reciprocal <---");
+                    process::exit(1);
+                }
+            },
+            "-t" => match args.next().as_deref() {
+                Some("own") => theta_cell = ThetaCell::Own,
+                Some("mean") => theta_cell = ThetaCell::Mean,
+                _ => {
+                    usage();
+                    println!("\n---> Option -t requires own or mean <---");
+                    process::exit(1);
+                }
+            },
+            "-i" => match args.next().map(|x| x.parse::<f64>()) {
+                Some(Ok(x)) if x > 0.0 => min_isa = Some(x),
+                _ => {
+                    usage();
+                    println!("\n---> Option -i requires a positive number <---");
+                    process::exit(1);
+                }
+            },
+            "-q" => isa_weight = true,
+            "-d" => match args.next().as_deref().map(|x| (x, x.parse::<f64>())) {
+                Some(("cc", _)) => cutoff = Some(Criterion::CcHalf),
+                Some((_, Ok(x))) if x > 0.0 => cutoff = Some(Criterion::ISigma(x)),
+                _ => {
+                    usage();
+                    println!("\n---> Option -d requires cc or a positive I/sigma <---");
+                    process::exit(1);
+                }
+            },
+            "-o" => match args.next().map(|x| x.parse::<f64>()) {
+                Some(Ok(x)) if x > 0.0 => outlier_sigma = Some(x),
+                _ => {
+                    usage();
+                    println!("\n---> Option -o requires a positive number <---");
+                    process::exit(1);
+                }
+            },
+            "-k" => keep_outliers = true,
+            "-n" => reindex = false,
+            "-a" => abort = true,
+            "-c" => match args.next().map(|x| x.parse::<f64>()) {
+                Some(Ok(x)) if x > 0.0 => cluster_threshold = Some(x),
+                _ => {
+                    usage();
+                    println!("\n---> Option -c requires a positive number <---");
+                    process::exit(1);
+                }
+            },
+            _ => filenames.push(arg),
         }
     }
-}
-
-// collection of experimental information for pcf-file
-#[derive(Clone)]
-struct Pcf {
-    file: String,
-    num_refl: i32,                           // from CORRECT.LP
-    detector: String,                        // from CORRECT.LP
-    nx: i32,                                 // from CORRECT.LP
-    ny: i32,                                 // from CORRECT.LP
-    qx: f32,                                 // from CORRECT.LP
-    qy: f32,                                 // from CORRECT.LP
-    distance: f32,                           // from CORRECT.LP
-    wavelength: f32,                         // from CORRECT.LP
-    cellabc: (f32, f32, f32, f32, f32, f32), // from CORRECT.LP (or Cell)
-    cellesd: (f32, f32, f32, f32, f32, f32), // from CORRECT.LP ( or Cell)
-}
 
-impl Default for Pcf {
-    fn default() -> Pcf {
-        Pcf {
-            file: String::new(),
-            num_refl: 0,
-            detector: String::new(),
-            nx: 0,
-            ny: 0,
-            qx: 0.0,
-            qy: 0.0,
-            distance: 0.0,
-            wavelength: 0.0,
-            cellabc: (0.0, 0.0, 0.0, 0.0, 0.0, 0.0),
-            cellesd: (0.0, 0.0, 0.0, 0.0, 0.0, 0.0),
+    // output file names and CIF data block follow the base name
+    let (hklout, pcfout, dataname) = match &base {
+        Some(b) => {
+            let name = std::path::Path::new(b)
+                .file_name()
+                .map(|n| n.to_string_lossy().to_string())
+                .unwrap_or(b.clone());
+            (format!("{b}.HKL"), format!("{b}.pcf"), name)
         }
-    }
-}
-// 3D vectors
-#[derive(Clone)]
-struct XYZ {
-    xyz: [f32; 3],
-}
-
-fn cross(x1: &XYZ, x2: &XYZ) -> XYZ {
-    let x = &x1.xyz[1] * &x2.xyz[2] - &x1.xyz[2] * &x2.xyz[1];
-    let y = &x1.xyz[2] * &x2.xyz[0] - &x1.xyz[0] * &x2.xyz[2];
-    let z = &x1.xyz[0] * &x2.xyz[1] - &x1.xyz[1] * &x2.xyz[0];
-
-    let xyz = XYZ { xyz: [x, y, z] };
-    xyz
-}
-
-impl Mul for XYZ {
-    type Output = f32;
-    fn mul(self, other: XYZ) -> f32 {
-        self.xyz[0] * other.xyz[0] + self.xyz[1] * other.xyz[1] + self.xyz[2] * other.xyz[2]
-    }
-}
+        None => (
+            String::from("my.HKL"),
+            String::from(PCFFILE),
+            String::from("my"),
+        ),
+    };
+    let opts = Options {
+        pcf: pcf_switch,
+        hklout,
+        pcfout,
+        dataname,
+        sg_policy,
+        su_mode,
+        average,
+        theta_cell,
+        outlier_sigma,
+        keep_outliers,
+        min_isa,
+        isa_weight,
+        cutoff,
+        reindex,
+        cluster_threshold,
+    };
 
-impl Mul<f32> for XYZ {
-    type Output = Self;
-    fn mul(self, s: f32) -> Self {
-        Self {
-            xyz: [s * self.xyz[0], s * self.xyz[1], s * self.xyz[2]],
+    welcome(&opts.pcfout);
+    // unusable files are skipped, or end the program with -a
+    let mut skipped: Vec<ParseError> = Vec::new();
+    for filename in filenames {
+        match rd_dataset(filename) {
+            Ok((cell, pcf)) => {
+                for w in &cell.warnings {
+                    println!("! Warning: {w}");
+                }
+                all_cells.push(cell);
+                all_pcfs.push(pcf);
+            }
+            Err(e) if abort => {
+                println!("\n---> {e} <---");
+                process::exit(1);
+            }
+            Err(e) => {
+                println!("! Skipping {e}");
+                skipped.push(e);
+            }
         }
     }
-}
-
-impl Mul<i32> for XYZ {
-    type Output = Self;
-    fn mul(self, si: i32) -> Self {
-        let s = si as f32;
-        Self {
-            xyz: [s * self.xyz[0], s * self.xyz[1], s * self.xyz[2]],
-        }
+    if all_cells.is_empty() {
+        usage();
+        summary(&skipped);
+        println!("\n---> Empty list of CORRECT.LP or XDS_ASCII.HKL files <---");
+        std::process::exit(1);
     }
-}
 
-impl Div<f32> for XYZ {
-    type Output = Self;
-    fn div(self, d: f32) -> Self {
-        let s = 1.0 / d;
-        Self {
-            xyz: [s * self.xyz[0], s * self.xyz[1], s * self.xyz[2]],
+    if common_radiation(&all_pcfs).is_none() {
+        for p in &all_pcfs {
+            println!("! {:8.5} A  {}", p.wavelength, p.file);
         }
+        println!("\n---> Mixed electron and X-ray data, refusing to average <---");
+        std::process::exit(1);
     }
-}
 
-impl Add for XYZ {
-    type Output = Self;
-    fn add(self, other: Self) -> Self {
-        Self {
-            xyz: [
-                self.xyz[0] + other.xyz[0],
-                self.xyz[1] + other.xyz[1],
-                self.xyz[2] + other.xyz[2],
-            ],
-        }
+    match opts.cluster_threshold {
+        Some(threshold) => merge_clusters(all_cells, all_pcfs, &opts, threshold),
+        None => match merge(all_cells, all_pcfs, &opts) {
+            Ok((xscale, pcf)) => {
+                print!("{xscale}");
+                if let Some(pcf) = pcf {
+                    write(&opts.pcfout, &pcf);
+                }
+            }
+            Err(failure) => fail(failure),
+        },
     }
+    summary(&skipped);
 }
 
-impl Sub for XYZ {
-    type Output = Self;
-    fn sub(self, other: Self) -> Self {
-        Self {
-            xyz: [
-                self.xyz[0] - other.xyz[0],
-                self.xyz[1] - other.xyz[1],
-                self.xyz[2] - other.xyz[2],
-            ],
-        }
-    }
+// why merge stops, with its output up to then
+struct Failure {
+    report: String,
+    reason: String,
 }
 
-fn volume(a: &XYZ, b: &XYZ, c: &XYZ) -> f32 {
-    let cstar = cross(a, b);
-    cstar * c.clone()
+fn fail(failure: Failure) -> ! {
+    print!("{}", failure.report);
+    println!("\n---> {} <---", failure.reason);
+    process::exit(1);
 }
 
-fn rec_cell(a: XYZ, b: XYZ, c: XYZ) -> (XYZ, XYZ, XYZ) {
-    let vol = volume(&a, &b, &c);
-    let astar = cross(&b, &c) / vol;
-    let bstar = cross(&c, &a) / vol;
-    let cstar = cross(&a, &b) / vol;
-    (astar, bstar, cstar)
+fn write(file: &str, content: &str) {
+    if let Err(e) = std::fs::write(file, content) {
+        println!("\n---> Unable to write {file}: {e} <---");
+        process::exit(1);
+    }
 }
 
-fn abc2vector(a: f32, b: f32, c: f32, alpha: f32, beta: f32, gamma: f32) -> (XYZ, XYZ, XYZ) {
-    let alpha = PI / 180.0 * alpha;
-    let beta = PI / 180.0 * beta;
-    let gamma = PI / 180.0 * gamma;
-    let avec = XYZ { xyz: [a, 0.0, 0.0] };
-    let bvec = XYZ {
-        xyz: [b * f32::cos(gamma), b * f32::sin(gamma), 0.0],
-    };
-    let c0 = c * f32::cos(beta);
-    let c1 = b * c * f32::cos(alpha) - bvec.xyz[0] * c0 / bvec.xyz[1];
-    let c2 = f32::sqrt(c * c - c0 * c0 - c1 * c1);
-    let cvec = XYZ { xyz: [c0, c1, c2] };
-
-    (avec, bvec, cvec)
+// files that were skipped, and why
+fn summary(skipped: &[ParseError]) {
+    if skipped.is_empty() {
+        return;
+    }
+    println!("!\n! {} file(s) skipped:", skipped.len());
+    for e in skipped {
+        println!("!   {:40} {}", e.file(), e.reason());
+    }
 }
 
-// workflow:
-// - read CORRECT.LP and accumulate into cells_*
-// - compute weighted cell and esds
-// - compute reciprocal cell
-// - read XDS_ASCII.HKL to compute dmin and dmax from
-//   weighted cell
-// - output XSCALE.INP
-// - write my.pcf
-//   TODO: provide argument '-r base' for base.HKL and base.pcf
-fn main() {
-    welcome();
-    let args: Vec<String> = env::args().collect();
-    // let has_esds = false;
-    let mut all_cells: Vec<Cell> = Vec::new();
-    let mut all_pcfs: Vec<Pcf> = Vec::new();
+// space group check, outlier rejection and weighted mean cell for
+// one set of data sets. Returns XSCALE.INP and, with -w, the data
+// block of the pcf file
+fn merge(
+    all_cells: Vec<Cell>,
+    all_pcfs: Vec<Pcf>,
+    opts: &Options,
+) -> Result<(String, Option<String>), Failure> {
+    let mut out = String::new();
     let mut cells_w_esu: Vec<Cell> = Vec::new();
     let mut cells_wo_esu: Vec<Cell> = Vec::new();
-    let mut pcf_switch: bool = false;
 
-    for idx in 1..args.len() {
-        let mut filename: String;
-        match args[idx].as_str() {
-            "-h" => {
-                usage();
-                process::exit(1);
-            }
-            "-w" => {
-                pcf_switch = true;
-                continue;
-            }
-            _ => filename = args[idx].clone(),
+    // data sets outside the majority space group are dropped
+    let (sg, keep) = match check_spacegroups(&mut out, &all_cells, opts.sg_policy) {
+        Ok(check) => check,
+        Err(e) => {
+            return Err(Failure {
+                report: out,
+                reason: e.to_string(),
+            })
         }
-        if std::path::Path::new(&filename).is_dir() == true {
-            filename += "/CORRECT.LP";
+    };
+    let (all_cells, all_pcfs): (Vec<Cell>, Vec<Pcf>) = all_cells
+        .into_iter()
+        .zip(all_pcfs)
+        .zip(keep)
+        .filter(|(_, k)| *k)
+        .map(|(cp, _)| cp)
+        .unzip();
+
+    // bring all data sets onto the setting of the first one
+    let reidx = if opts.reindex && all_cells.len() > 1 {
+        reindex::reindex(&mut out, &all_cells, sg)
+    } else {
+        vec![Reindexing::identity(); all_cells.len()]
+    };
+    let all_cells: Vec<Cell> = all_cells
+        .iter()
+        .zip(&reidx)
+        .map(|(c, r)| reindex::apply(c, &r.p))
+        .collect();
+
+    // data sets below the minimum ISa are left out altogether
+    let good_isa = match opts.min_isa {
+        Some(min) => reject_low_isa(&mut out, &all_cells, min),
+        None => vec![true; all_cells.len()],
+    };
+
+    // rejected data sets do not contribute to the mean cell
+    let active: Vec<bool> = match opts.outlier_sigma {
+        Some(nsigma) => {
+            let good: Vec<Cell> = all_cells
+                .iter()
+                .zip(&good_isa)
+                .filter(|(_, g)| **g)
+                .map(|(c, _)| c.clone())
+                .collect();
+            let mut kept = reject_outliers(&mut out, &good, nsigma).into_iter();
+            good_isa
+                .iter()
+                .map(|g| *g && kept.next().unwrap_or(false))
+                .collect()
         }
-        let (cell, pcf) = match rd_correct(filename) {
-            Some((cell, pcf)) => (cell, pcf),
-            None => continue,
-        };
-        all_cells.push(cell.clone());
-        all_pcfs.push(pcf);
+        None => good_isa.clone(),
+    };
+    for (cell, _) in all_cells.iter().zip(&active).filter(|(_, a)| **a) {
         if cell.sg == -1 || cell.a_esu == -1.0 {
-            cells_wo_esu.push(cell);
+            cells_wo_esu.push(cell.clone());
         } else {
-            cells_w_esu.push(cell);
+            cells_w_esu.push(cell.clone());
         }
     }
-    if all_cells.len() == 0 {
-        usage();
-        println!("\n---> Empty list of CORRECT.LP files <---");
-        std::process::exit(1);
-    }
-
-    if cells_w_esu.len() == 0 && cells_wo_esu.len() == 0 {
-        panic!("No cells found at all, exiting");
+    if cells_w_esu.is_empty() && cells_wo_esu.is_empty() {
+        return Err(Failure {
+            report: out,
+            reason: String::from(
+                "No data set left to average, all below the minimum ISa or rejected",
+            ),
+        });
     }
-    let sg = if cells_w_esu.len() > 0 {
-        cells_w_esu[0].sg
-    } else {
-        cells_wo_esu[0].sg
-    };
 
-    let mcell: Cell;
-    // no esu's available, take standard average
-    if cells_w_esu.len() < 1 {
-        let sigmas = vec![1.0; cells_wo_esu.len()];
-
-        let vals: Vec<_> = cells_wo_esu.iter().map(|p| p.a).collect();
-        let (amean, aesu) = wmean(&vals, &sigmas);
-
-        let vals: Vec<_> = cells_wo_esu.iter().map(|p| p.b).collect();
-        let (bmean, besu) = wmean(&vals, &sigmas);
-
-        let vals: Vec<_> = cells_wo_esu.iter().map(|p| p.c).collect();
-        let (cmean, cesu) = wmean(&vals, &sigmas);
-
-        let vals: Vec<_> = cells_wo_esu.iter().map(|p| p.alpha).collect();
-        let (alphamean, alphaesu) = wmean(&vals, &sigmas);
-
-        let vals: Vec<_> = cells_wo_esu.iter().map(|p| p.beta).collect();
-        let (betamean, betaesu) = wmean(&vals, &sigmas);
-
-        let vals: Vec<_> = cells_wo_esu.iter().map(|p| p.gamma).collect();
-        let (gammamean, gammaesu) = wmean(&vals, &sigmas);
-        mcell = Cell {
-            file: String::new(),
-            sg: sg,
-            a: amean,
-            b: bmean,
-            c: cmean,
-            alpha: alphamean,
-            beta: betamean,
-            gamma: gammamean,
-            a_esu: aesu,
-            b_esu: besu,
-            c_esu: cesu,
-            alpha_esu: alphaesu,
-            beta_esu: betaesu,
-            gamma_esu: gammaesu,
+    // no esu's available, take standard average; only the
+    // external esu is meaningful then, and none for a single data set
+    let (mcell, stats, su_mode) = if cells_w_esu.is_empty() {
+        let su_mode = if cells_wo_esu.len() < 2 {
+            SuMode::Unknown
+        } else {
+            SuMode::External
         };
+        let (mcell, stats) = mean_cell(&cells_wo_esu, sg, false, su_mode, opts.average);
+        (mcell, stats, su_mode)
     } else {
-        let vals: Vec<_> = cells_w_esu.iter().map(|p| p.a).collect();
-        let esus: Vec<_> = cells_w_esu.iter().map(|p| p.a_esu).collect();
-        let (amean, aesu) = wmean(&vals, &esus);
-
-        let vals: Vec<_> = cells_w_esu.iter().map(|p| p.b).collect();
-        let esus: Vec<_> = cells_w_esu.iter().map(|p| p.b_esu).collect();
-        let (bmean, besu) = wmean(&vals, &esus);
-
-        let vals: Vec<_> = cells_w_esu.iter().map(|p| p.c).collect();
-        let esus: Vec<_> = cells_w_esu.iter().map(|p| p.c_esu).collect();
-        let (cmean, cesu) = wmean(&vals, &esus);
-
-        let vals: Vec<_> = cells_w_esu.iter().map(|p| p.alpha).collect();
-        let esus: Vec<_> = cells_w_esu.iter().map(|p| p.alpha_esu).collect();
-        let (alphamean, alphaesu) = wmean(&vals, &esus);
-
-        let vals: Vec<_> = cells_w_esu.iter().map(|p| p.beta).collect();
-        let esus: Vec<_> = cells_w_esu.iter().map(|p| p.beta_esu).collect();
-        let (betamean, betaesu) = wmean(&vals, &esus);
-
-        let vals: Vec<_> = cells_w_esu.iter().map(|p| p.gamma).collect();
-        let esus: Vec<_> = cells_w_esu.iter().map(|p| p.gamma_esu).collect();
-        let (gammamean, gammaesu) = wmean(&vals, &esus);
-
-        mcell = Cell {
-            file: String::new(),
-            sg: sg,
-            a: amean,
-            b: bmean,
-            c: cmean,
-            alpha: alphamean,
-            beta: betamean,
-            gamma: gammamean,
-            a_esu: aesu,
-            b_esu: besu,
-            c_esu: cesu,
-            alpha_esu: alphaesu,
-            beta_esu: betaesu,
-            gamma_esu: gammaesu,
-        };
-    }
+        if !cells_wo_esu.is_empty() {
+            out += &format!(
+                "! {} data set(s) without esu's left out of the weighted mean:\n",
+                cells_wo_esu.len()
+            );
+            for cell in &cells_wo_esu {
+                out += &format!("!   {} ({})\n", cell.file, cell.source.describe());
+            }
+        }
+        if opts.isa_weight {
+            out += "! weights of the mean scaled by (ISa/<ISa>)^2\n";
+            cells_w_esu = isa_weighted(&cells_w_esu);
+        }
+        let (mcell, stats) = mean_cell(&cells_w_esu, sg, true, opts.su_mode, opts.average);
+        (mcell, stats, opts.su_mode)
+    };
 
-    xscaleinp(all_cells, mcell.clone());
-    if pcf_switch == true {
-        write_pcf(all_pcfs, &mcell);
-    }
+    // outliers stay in XSCALE.INP only with -k, data sets with low
+    // ISa never
+    let inputs: Vec<Input> = good_isa
+        .iter()
+        .zip(&active)
+        .map(|(g, a)| match (g, a) {
+            (false, _) => Input::LowIsa,
+            (true, false) if !opts.keep_outliers => Input::Outlier,
+            _ => Input::Merge,
+        })
+        .collect();
+    let (merged_pcfs, settings): (Vec<Pcf>, Vec<Matrix>) = all_pcfs
+        .into_iter()
+        .zip(&reidx)
+        .zip(&inputs)
+        .filter(|(_, i)| **i == Input::Merge)
+        .map(|((p, r), _)| (p, r.p))
+        .unzip();
+    xscaleinp(
+        &mut out,
+        all_cells,
+        &inputs,
+        &reidx,
+        mcell.clone(),
+        &stats,
+        su_mode,
+        opts.average,
+        opts.cutoff,
+        &opts.hklout,
+    );
+    let pcf = opts.pcf.then(|| {
+        pcf_block(
+            &mut out,
+            merged_pcfs,
+            &settings,
+            &mcell,
+            opts.theta_cell,
+            &opts.dataname,
+        )
+    });
+    Ok((out, pcf))
 }
 
-// compute weighted mean
-// if one sigma == 0, assume this is constraint, return
-// first value and 0
-fn wmean(vals: &Vec<f32>, sigmas: &Vec<f32>) -> (f32, f32) {
-    if sigmas[0] == 0.0 {
-        return (vals[0], 0.0);
-    }
-    let mut mean: f32 = 0.0;
-
-    let mut sumsigma2: f32 = 0.0;
-
-    for it in vals.iter().zip(sigmas.iter()) {
-        let (x, s) = it;
-        mean += *x / (*s * *s);
-        sumsigma2 += 1.0 / (*s * *s);
+// cluster the cells and write one XSCALE.INP per cluster to the
+// directory clusterN, N = 1, 2, ... Nothing is written unless all
+// clusters can be merged
+fn merge_clusters(cells: Vec<Cell>, pcfs: Vec<Pcf>, opts: &Options, threshold: f64) {
+    let tree = cluster::cluster(&cells);
+    let clusters = tree.cut(threshold);
+    print!("{}", cluster::dendrogram(&tree, &cells, &clusters));
+    println!("! clusters below {threshold:.2} %:");
+
+    // XSCALE runs inside the cluster directory, hence absolute paths
+    let absolute = |f: &str| -> String {
+        std::fs::canonicalize(f)
+            .map(|p| p.to_string_lossy().to_string())
+            .unwrap_or(f.to_string())
+    };
+    let file_name = |f: &str| -> String {
+        std::path::Path::new(f)
+            .file_name()
+            .map(|n| n.to_string_lossy().to_string())
+            .unwrap_or(f.to_string())
+    };
+    let mut merged = Vec::new();
+    for (n, members) in clusters.iter().enumerate() {
+        let dir = format!("cluster{}", n + 1);
+        let mut ccells: Vec<Cell> = Vec::new();
+        let mut cpcfs: Vec<Pcf> = Vec::new();
+        for &i in members {
+            let mut cell = cells[i].clone();
+            cell.file = absolute(&cell.file);
+            ccells.push(cell);
+            let mut pcf = pcfs[i].clone();
+            pcf.file = absolute(&pcf.file);
+            cpcfs.push(pcf);
+        }
+        let copts = Options {
+            hklout: file_name(&opts.hklout),
+            pcfout: format!("{dir}/{}", file_name(&opts.pcfout)),
+            ..opts.clone()
+        };
+        let content = format!(
+            "! Cluster {} of {} from weightedcell, {} data set(s)\n",
+            n + 1,
+            clusters.len(),
+            members.len()
+        );
+        match merge(ccells, cpcfs, &copts) {
+            Ok((xscale, pcf)) => {
+                merged.push((dir, content + &xscale, pcf.map(|p| (copts.pcfout, p))))
+            }
+            Err(failure) => fail(Failure {
+                report: content + &failure.report,
+                ..failure
+            }),
+        }
     }
 
-    mean /= sumsigma2;
-
-    let mut sums: f32 = 0.0;
-    let mut sumw: f32 = 0.0;
-    for it in vals.iter().zip(sigmas.iter()) {
-        let (x, s) = it;
-        let w = 1.0 - (mean - *x) * (mean - *x) / (mean * mean);
-        sums += w * *s;
-        sumw += w;
+    for ((n, (dir, xscale, pcf)), members) in merged.into_iter().enumerate().zip(&clusters) {
+        if let Err(e) = std::fs::create_dir_all(&dir) {
+            println!("\n---> Unable to create {dir}: {e} <---");
+            process::exit(1);
+        }
+        write(&format!("{dir}/XSCALE.INP"), &xscale);
+        if let Some((pcfout, pcf)) = pcf {
+            write(&pcfout, &pcf);
+        }
+        println!(
+            "!   cluster {:3}: {:3} data set(s) -> {dir}/XSCALE.INP",
+            n + 1,
+            members.len()
+        );
     }
-    let sigma: f32 = sums / sumw;
-
-    (mean, sigma)
 }
 
 fn usage() {
-    println!("Usage: weightedcell <one or more CORRECT.LP> [-w]\n");
+    println!("Usage: weightedcell <one or more CORRECT.LP, XDS_ASCII.HKL or directories>");
+    println!("                    [-w] [-r base]\n");
     println!("      -w: Create file weightedcell.pcf with CIF keywords");
-    println!("          including some experimental data\n");
+    println!("          including some experimental data");
+    println!("      -r base: write OUTPUT_FILE= base.HKL to XSCALE.INP and");
+    println!("          CIF keywords to base.pcf with data block data_base");
+    println!("      -s fail|majority|subgroup: for data sets in different space");
+    println!("          groups, stop (default), use the majority group and drop");
+    println!("          the others, or use the highest common subgroup");
+    println!("      -e internal|external|max: e.s.u. of the mean cell from the");
+    println!("          weights, from the scatter of the cells, or the larger");
+    println!("          of both (default)");
+    println!("      -m param|metric|reciprocal: average the cell parameters");
+    println!("          (default), the metric tensors G, or the reciprocal");
+    println!("          metric tensors G*");
+    println!("      -t own|mean: theta range of each crystal in the pcf file from");
+    println!("          its own cell (default) or from the mean cell");
+    println!("      -o nsigma: reject data sets whose cell deviates by more than");
+    println!("          nsigma from the others before averaging");
+    println!("      -k: keep rejected data sets as INPUT_FILE in XSCALE.INP");
+    println!("      -i isa: leave out data sets with ISa below isa");
+    println!("      -q: scale the weights of the mean cell by (ISa/<ISa>)^2");
+    println!("      -d cc|isigma: write INCLUDE_RESOLUTION_RANGE for each data set,");
+    println!("          up to the last shell of CORRECT.LP with CC1/2 significant");
+    println!("          at the 0.1 % level, or with I/sigma of at least isigma");
+    println!("      -n: no reindexing onto the setting of the first data set");
+    println!("      -a: abort on the first unreadable input file instead of");
+    println!("          skipping it");
+    println!("      -c threshold: cluster the cells (G6 distance in %, average");
+    println!("          linkage) and write clusterN/XSCALE.INP for each cluster\n");
     println!("       e.g. #> weightedcell ../run | tee XSCALE.INP");
     println!("       or   #> weightedcell ../run/CORRECT.LP | tee XSCALE.INP");
+    println!("       A directory without CORRECT.LP, or an XDS_ASCII.HKL, is");
+    println!("       read from the header of XDS_ASCII.HKL, without esu's");
 }
 
-fn welcome() {
+fn welcome(pcffile: &str) {
     let now = match env::var("SOURCE_DATE_EPOCH") {
         Ok(val) => Utc.timestamp_opt(val.parse::<i64>().unwrap(), 0).unwrap(),
         Err(_) => Utc::now(),
@@ -385,343 +550,7 @@ fn welcome() {
     println!("!  Weighted cell parameters from XDS CORRECT.LP                 !");
     println!("!  Version 01/2025, (c) Tim Gruene                              !");
     println!("!  tim.gruene@univie.ac.at                                      !");
-    println!(
-        "!  Experimental CIF entries written to {:10}         !",
-        PCFFILE
-    );
+    println!("!  Experimental CIF entries written to {:16}   !", pcffile);
     println!("!  Built {:-30}                      !", now);
     println!("! --------------------------------------------------------------!");
 }
-
-// read CORRECT.LP; path provided by filename
-// assume to be valid path
-// return true if esds are available
-// if
-fn rd_correct(filename: String) -> Option<(Cell, Pcf)> {
-    let mut mycell = Cell::default();
-    mycell.file = filename.clone();
-    let mut mypcf = Pcf::default();
-    mypcf.file = filename;
-
-    let correctlp_result = std::fs::read_to_string(mycell.file.clone());
-    let correctlp = match correctlp_result {
-        Ok(lines) => lines,
-        Err(_) => String::new(),
-    };
-    if correctlp.len() == 0 {
-        return None;
-    }
-
-    let mut proper_correctlp: bool = false;
-    // error handling is done, can use lines() directly
-    for l in correctlp.lines() {
-        if l.contains(" ***** CORRECT ***** (VERSION") {
-            proper_correctlp = true;
-            continue;
-        }
-        ///////////////////////////////////////////////
-        // PCF Details                               //
-        ///////////////////////////////////////////////
-        if l.contains(" X-RAY_WAVELENGTH=") {
-            let w: Vec<&str> = l.split_whitespace().collect();
-            let wavelength = match w[1].trim().parse::<f32>() {
-                Ok(l) => l,
-                Err(_) => {
-                    println!("Could not read wavelength from {0}", mycell.file);
-                    return None;
-                }
-            };
-            mypcf.wavelength = wavelength;
-            continue;
-        }
-        if l.contains(" DETECTOR=") {
-            let w: Vec<&str> = l.split('=').collect();
-            let detector = w[1];
-            mypcf.detector = detector.to_string();
-            continue;
-        }
-        if l.contains(" NX=") {
-            let w: Vec<&str> = l.split_whitespace().collect();
-            let x = w[1].trim().parse::<i32>();
-            mypcf.nx = x.expect("Error: unable to read NX.");
-            let x = w[3].trim().parse::<i32>();
-            mypcf.ny = x.expect("Error: unable to read NY.");
-            let x = match w[5].trim().parse::<f32>() {
-                Ok(q) => q,
-                Err(_) => {
-                    return None;
-                }
-            };
-            mypcf.qx = x;
-            let x = w[7].trim().parse::<f32>();
-            mypcf.qy = x.expect("Error: unable to read QY.");
-            continue;
-        }
-        if l.contains(" DETECTOR_DISTANCE=") {
-            let w: Vec<&str> = l.split_whitespace().collect();
-            let dist = w[1].trim().parse::<f32>();
-            mypcf.distance = dist.expect("Error: unable to read {D} as detector distance.");
-            continue;
-        }
-        if l.contains(" INDEXED SPOTS") {
-            let w: Vec<&str> = l.split_whitespace().collect();
-            let x = w[7].trim().parse::<i32>();
-            mypcf.num_refl = x.expect("Error: unable to extract indexed spots from {x}.");
-            continue;
-        }
-
-        ///////////////////////////////////////////////
-        // XSCALE.INP Details                        //
-        ///////////////////////////////////////////////
-        if l.contains(" SPACE GROUP NUMBER ") {
-            let w: Vec<&str> = l.split_whitespace().collect();
-            let sg = w[3].trim().parse::<i32>();
-            mycell.sg = sg.expect("Error: unable to convert {sg} to SG number");
-            continue;
-            // next line should be cell and ESDs
-        }
-        if l.contains(" UNIT CELL PARAMETERS ") {
-            let w: Vec<&str> = l.split_whitespace().collect();
-            let a = w[3].trim().parse::<f32>();
-            let b = w[4].trim().parse::<f32>();
-            let c = w[5].trim().parse::<f32>();
-            let alpha = w[6].trim().parse::<f32>();
-            let beta = w[7].trim().parse::<f32>();
-            let gamma = w[8].trim().parse::<f32>();
-            mycell.a = a.expect("Error Cell: unable to convert {a} to float");
-            mycell.b = b.expect("Error Cell: unable to convert {a} to float");
-            mycell.c = c.expect("Error Cell: unable to convert {a} to float");
-            mycell.alpha = alpha.expect("Error Cell: unable to convert {a} to float");
-            mycell.beta = beta.expect("Error Cell: unable to convert {a} to float");
-            mycell.gamma = gamma.expect("Error Cell: unable to convert {a} to float");
-            continue;
-        }
-        if l.contains(" E.S.D. OF CELL PARAMETERS") {
-            let w: Vec<&str> = l.split_whitespace().collect();
-            if w.len() == 5 {
-                mycell.a_esu = -1.0;
-                mycell.b_esu = -1.0;
-                mycell.c_esu = -1.0;
-                mycell.alpha_esu = -1.0;
-                mycell.beta_esu = -1.0;
-                mycell.gamma_esu = -1.0;
-            } else {
-                let a = w[4].trim().parse::<f32>();
-                let b = w[5].trim().parse::<f32>();
-                let c = w[6].trim().parse::<f32>();
-                let alpha = w[7].trim().parse::<f32>();
-                let beta = w[8].trim().parse::<f32>();
-                let gamma = w[9].trim().parse::<f32>();
-                mycell.a_esu = a.expect("Error ESU: unable to convert {a} to float");
-                mycell.b_esu = b.expect("Error ESU: unable to convert {a} to float");
-                mycell.c_esu = c.expect("Error ESU: unable to convert {a} to float");
-                mycell.alpha_esu = alpha.expect("Error ESU: unable to convert {a} to float");
-                mycell.beta_esu = beta.expect("Error ESU: unable to convert {a} to float");
-                mycell.gamma_esu = gamma.expect("Error ESU: unable to convert {a} to float");
-            }
-            continue;
-        }
-    }
-    // need to capture the possibility that not all values were set (incorrect
-    // CORRECT.LP
-    if proper_correctlp == false {
-        return None;
-    }
-    // update mypcf with cell information
-    mypcf.cellabc = (
-        mycell.a,
-        mycell.b,
-        mycell.c,
-        mycell.alpha,
-        mycell.beta,
-        mycell.gamma,
-    );
-    mypcf.cellesd = (
-        mycell.a_esu,
-        mycell.b_esu,
-        mycell.c_esu,
-        mycell.alpha_esu,
-        mycell.beta_esu,
-        mycell.gamma_esu,
-    );
-    Some((mycell, mypcf))
-}
-
-fn printcell(cell: &Cell) {
-    println!("!---> {}", cell.file);
-    println!(
-        "!     cell {:8.3}{:8.3}{:8.3}{:9.3}{:9.3}{:9.3}",
-        cell.a, cell.b, cell.c, cell.alpha, cell.beta, cell.gamma
-    );
-    println!(
-        "!     esu  {:7.3}{:7.3}{:7.3}{:7.3}{:7.3}{:7.3}",
-        cell.a_esu, cell.b_esu, cell.c_esu, cell.alpha_esu, cell.beta_esu, cell.gamma_esu
-    );
-}
-
-// replace CORRECT.LP with XDS_ASCII.HKL and print
-fn printinp(cell: &Cell) {
-    let s = cell.file.replace("CORRECT.LP", "XDS_ASCII.HKL");
-    println!(" INPUT_FILE= {s}");
-}
-
-// summarise information and print as valid XSCALE.INP
-// to stdout
-fn xscaleinp(cells: Vec<Cell>, mcell: Cell) {
-    for c in &cells {
-        printcell(c);
-    }
-    println!("!=========================================================================");
-    println!(
-        "! Mean cell: {:8.4} {:8.4} {:8.4} {:9.3} {:9.3} {:9.3}",
-        mcell.a, mcell.b, mcell.c, mcell.alpha, mcell.beta, mcell.gamma
-    );
-    println!(
-        "!   e.s.u's: {:8.4} {:8.4} {:8.4} {:8.3} {:8.3} {:8.3}",
-        mcell.a_esu, mcell.b_esu, mcell.c_esu, mcell.alpha_esu, mcell.beta_esu, mcell.gamma_esu
-    );
-
-    println!("\n OUTPUT_FILE= my.HKL");
-    println!("\n SPACE_GROUP_NUMBER= {}", mcell.sg);
-    println!(
-        " UNIT_CELL_CONSTANTS= {:8.4} {:8.4} {:8.4} {:8.3} {:8.3} {:8.3}",
-        mcell.a, mcell.b, mcell.c, mcell.alpha, mcell.beta, mcell.gamma
-    );
-    for c in &cells {
-        printinp(c);
-    }
-}
-
-// extract dstarmin and dstarmax from XDS_ASCII.HKL
-fn resolution_range(xdsascii: &String, cell: &Cell) -> (f32, f32) {
-    let mut dstarmin = f32::INFINITY;
-    let mut dstarmax = -f32::INFINITY;
-
-    let (avec, bvec, cvec) = abc2vector(cell.a, cell.b, cell.c, cell.alpha, cell.beta, cell.gamma);
-    let (astar, bstar, cstar): (XYZ, XYZ, XYZ) = rec_cell(avec, bvec, cvec);
-
-    let inp = std::fs::read_to_string(xdsascii);
-    for l in inp.expect("Invalid line").lines() {
-        let p = l.chars().nth(0);
-        if p == Some('!') {
-            continue;
-        }
-        let p: Vec<&str> = l.split_whitespace().collect();
-        let h = p[0].trim().parse::<i32>();
-        let h = h.expect("Error: unable to extract h from {p[0]}");
-        let k = p[1].trim().parse::<i32>();
-        let k = k.expect("Error: unable to extract h from {p[1]}");
-        let l = p[2].trim().parse::<i32>();
-        let l = l.expect("Error: unable to extract h from {p[2]}");
-        let dstar = reciprocal_d_spacing(h, k, l, astar.clone(), bstar.clone(), cstar.clone());
-        if dstar > dstarmax {
-            dstarmax = dstar;
-        }
-        if dstar < dstarmin {
-            dstarmin = dstar;
-        }
-    }
-    let dstarmin = f32::sqrt(dstarmin);
-    let dstarmax = f32::sqrt(dstarmax);
-
-    (dstarmin, dstarmax)
-}
-
-// compute reciprocal resolution squared
-fn reciprocal_d_spacing(h: i32, k: i32, l: i32, a: XYZ, b: XYZ, c: XYZ) -> f32 {
-    let p: XYZ = a * h + b * k + c * l;
-    p.clone() * p
-}
-
-fn write_pcf(pcfs: Vec<Pcf>, mcell: &Cell) {
-    let mut content = String::from("data_my\n");
-    content += &String::from("loop_\n");
-    content += &String::from("_exptl_crystal_id\n");
-    content += &String::from("_cell_length_a\n");
-    content += &String::from("_cell_length_b\n");
-    content += &String::from("_cell_length_c\n");
-    content += &String::from("_cell_angle_alpha\n");
-    content += &String::from("_cell_angle_beta\n");
-    content += &String::from("_cell_angle_gamma\n");
-    content += &String::from("_cell_measurement_reflns_used\n");
-    content += &String::from("_cell_measurement_theta_min\n");
-    content += &String::from("_cell_measurement_theta_max\n");
-    let mut id = 1;
-    let mut global_refl_used: i32 = 0;
-    let mut global_thetamin: f32 = 180.0;
-    let mut global_thetamax: f32 = 0.0;
-    for x in pcfs {
-        // get dmin and dmax from XDS_ASCII.HKL
-        let mut filename = x.file;
-        if std::path::Path::new(&filename).is_dir() == true {
-            filename += "/CORRECT.LP";
-        }
-        let filename = filename.replace("CORRECT.LP", "XDS_ASCII.HKL");
-        let (dstarmin, dstarmax) = resolution_range(&filename, mcell);
-        let thetamin = f32::asin(f32::min(1.0, 0.5 * dstarmin * x.wavelength));
-        let thetamax = f32::asin(f32::min(1.0, 0.5 * dstarmax * x.wavelength));
-        let (a, b, c, al, be, ga) = x.cellesd;
-        let (pa, a) = precision(a);
-        let (pb, b) = precision(b);
-        let (pc, c) = precision(c);
-        let (pal, al) = precision(al);
-        let (pbe, be) = precision(be);
-        let (pga, ga) = precision(ga);
-        let s = format!(
-            "{id:-3} \
-		 {0:0>.1$}({a}) \
-		 {2:0>.3$}({b}) \
-		 {4:0>.5$}({c}) \
-		 {6:0>.7$}({al}) \
-		 {8:0>.9$}({be}) \
-		 {10:0>.11$}({ga}) \
-		 {12:6} \
-		 {13:3.2} \
-		 {14:3.2} \
-		\n",
-            x.cellabc.0,
-            pa,
-            x.cellabc.1,
-            pb,
-            x.cellabc.2,
-            pc,
-            x.cellabc.3,
-            pal,
-            x.cellabc.4,
-            pbe,
-            x.cellabc.5,
-            pga,
-            x.num_refl,
-            180.0 / std::f32::consts::PI * thetamin,
-            180.0 / std::f32::consts::PI * thetamax
-        );
-        global_refl_used += x.num_refl;
-        global_thetamin = f32::min(global_thetamin, 180.0 / std::f32::consts::PI * thetamin);
-        global_thetamax = f32::max(global_thetamax, 180.0 / std::f32::consts::PI * thetamax);
-        content += &s;
-        id += 1;
-    }
-    let s = format!("\n_cell_measurement_reflns_used    {}\n", global_refl_used);
-    content += &s;
-    let s = format!("_cell_measurement_theta_min      {:4.2}\n", global_thetamin);
-    content += &s;
-    let s = format!("_cell_measurement_theta_max      {:4.2}\n", global_thetamax);
-    content += &s;
-
-    std::fs::write(PCFFILE, content).expect("Unable to write to PCF file");
-}
-
-// for a number < 1 return its precision
-fn precision(x: f32) -> (usize, i32) {
-    if x <= 0.0 {
-        return (0, 0);
-    }
-    let mut v = x;
-    let mut precision = 0;
-    while v < 1.0 {
-        v *= 10.0;
-        precision += 1;
-    }
-    let p = v as i32;
-    (precision, p)
-}
@@ -0,0 +1,331 @@
+//! Niggli reduction of unit cells after Krivy & Gruber (1976), with
+//! the epsilon tests of Grosse-Kunstleve, Sauter & Adams (2004).
+//! Centred cells are first transformed to a primitive cell. The cell
+//! is set up as basis vectors; every step applies an integer matrix
+//! to the basis and the G6 parameters are recomputed from the new
+//! vectors, so rounding errors do not accumulate
+
+use crate::cell::{abc2vector, volume, Cell, XYZ};
+use crate::spacegroup;
+
+/// (a',b',c') = (a,b,c) P, i.e. the columns of P are the new axes
+pub type Matrix = [[i32; 3]; 3];
+
+/// identity transformation
+pub const IDENTITY: Matrix = [[1, 0, 0], [0, 1, 0], [0, 0, 1]];
+
+// safeguard against cycling due to rounding
+const MAXCYCLES: usize = 1000;
+
+// relative tolerance, scaled by V^(2/3)
+const EPSILON: f64 = 1.0e-5;
+
+/// matrix product P Q
+pub fn matmul(p: &Matrix, q: &Matrix) -> Matrix {
+    let mut r = [[0; 3]; 3];
+    for (i, row) in r.iter_mut().enumerate() {
+        for (j, x) in row.iter_mut().enumerate() {
+            *x = (0..3).map(|k| p[i][k] * q[k][j]).sum();
+        }
+    }
+    r
+}
+
+/// determinant of P
+pub fn det(p: &Matrix) -> i32 {
+    p[0][0] * (p[1][1] * p[2][2] - p[1][2] * p[2][1])
+        - p[0][1] * (p[1][0] * p[2][2] - p[1][2] * p[2][0])
+        + p[0][2] * (p[1][0] * p[2][1] - p[1][1] * p[2][0])
+}
+
+/// inverse of a unimodular matrix (det = 1), i.e. the adjugate
+pub fn inverse(p: &Matrix) -> Matrix {
+    let mut r = [[0; 3]; 3];
+    for (i, row) in r.iter_mut().enumerate() {
+        for (j, x) in row.iter_mut().enumerate() {
+            let (j1, j2) = ((j + 1) % 3, (j + 2) % 3);
+            let (i1, i2) = ((i + 1) % 3, (i + 2) % 3);
+            *x = p[j1][i1] * p[j2][i2] - p[j1][i2] * p[j2][i1];
+        }
+    }
+    r
+}
+
+fn diag(i: i32, j: i32, k: i32) -> Matrix {
+    [[i, 0, 0], [0, j, 0], [0, 0, k]]
+}
+
+// basis vectors (a,b,c) P
+fn transform(abc: &[XYZ; 3], p: &Matrix) -> [XYZ; 3] {
+    let col =
+        |j: usize| abc[0].clone() * p[0][j] + abc[1].clone() * p[1][j] + abc[2].clone() * p[2][j];
+    [col(0), col(1), col(2)]
+}
+
+// primitive basis of a centred cell (International Tables A,
+// Table 5.1.3.1), R in hexagonal axes, obverse setting
+fn primitive(abc: [XYZ; 3], lattice: char) -> [XYZ; 3] {
+    let [a, b, c] = abc;
+    match lattice {
+        'A' => [a, (b.clone() - c.clone()) / 2.0, (b + c) / 2.0],
+        'B' => [(a.clone() - c.clone()) / 2.0, b, (a + c) / 2.0],
+        'C' => [(a.clone() - b.clone()) / 2.0, (a + b) / 2.0, c],
+        'I' => [
+            (b.clone() + c.clone() - a.clone()) / 2.0,
+            (a.clone() + c.clone() - b.clone()) / 2.0,
+            (a + b - c) / 2.0,
+        ],
+        'F' => [
+            (b.clone() + c.clone()) / 2.0,
+            (a.clone() + c.clone()) / 2.0,
+            (a + b) / 2.0,
+        ],
+        'R' => [
+            (a.clone() * 2 + b.clone() + c.clone()) / 3.0,
+            (b.clone() + c.clone() - a.clone()) / 3.0,
+            (c - a - b * 2) / 3.0,
+        ],
+        _ => [a, b, c],
+    }
+}
+
+// A, B, C, xi, eta, zeta
+fn g6(v: &[XYZ; 3]) -> [f64; 6] {
+    let dot = |i: usize, j: usize| v[i].clone() * v[j].clone();
+    [
+        dot(0, 0),
+        dot(1, 1),
+        dot(2, 2),
+        2.0 * dot(1, 2),
+        2.0 * dot(0, 2),
+        2.0 * dot(0, 1),
+    ]
+}
+
+// one step of the reduction: the matrix of the first condition
+// N1 - N8 that applies, None if the cell is reduced
+fn step(g: &[f64; 6], eps: f64) -> Option<Matrix> {
+    let [a, b, c, xi, eta, zeta] = *g;
+    let lt = |x: f64, y: f64| x < y - eps;
+    let gt = |x: f64, y: f64| y < x - eps;
+    let eq = |x: f64, y: f64| !(lt(x, y) || gt(x, y));
+    let sign = |x: f64| if x > 0.0 { 1 } else { -1 };
+
+    // N1, N2: order A <= B <= C
+    if gt(a, b) || (eq(a, b) && gt(xi.abs(), eta.abs())) {
+        return Some([[0, -1, 0], [-1, 0, 0], [0, 0, -1]]);
+    }
+    if gt(b, c) || (eq(b, c) && gt(eta.abs(), zeta.abs())) {
+        return Some([[-1, 0, 0], [0, 0, -1], [0, -1, 0]]);
+    }
+
+    // N3, N4: all angles acute or all angles obtuse
+    let mut n_zero = 0;
+    let mut n_positive = 0;
+    for x in [xi, eta, zeta] {
+        if gt(x, 0.0) {
+            n_positive += 1;
+        } else if !lt(x, 0.0) {
+            n_zero += 1;
+        }
+    }
+    let m = if n_positive == 3 || (n_zero == 0 && n_positive == 1) {
+        let s = |x: f64| if lt(x, 0.0) { -1 } else { 1 };
+        diag(s(xi), s(eta), s(zeta))
+    } else {
+        let mut ijk = [1, 1, 1];
+        let mut zero: Option<usize> = None;
+        for (n, x) in [xi, eta, zeta].into_iter().enumerate() {
+            if gt(x, 0.0) {
+                ijk[n] = -1;
+            } else if !lt(x, 0.0) {
+                zero = Some(n);
+            }
+        }
+        if ijk[0] * ijk[1] * ijk[2] < 0 {
+            if let Some(n) = zero {
+                ijk[n] = -1;
+            }
+        }
+        diag(ijk[0], ijk[1], ijk[2])
+    };
+    if m != IDENTITY {
+        return Some(m);
+    }
+
+    // N5 - N7: reduce the off-diagonal terms
+    if gt(xi.abs(), b) || (eq(xi, b) && lt(2.0 * eta, zeta)) || (eq(xi, -b) && lt(zeta, 0.0)) {
+        return Some([[1, 0, 0], [0, 1, -sign(xi)], [0, 0, 1]]);
+    }
+    if gt(eta.abs(), a) || (eq(eta, a) && lt(2.0 * xi, zeta)) || (eq(eta, -a) && lt(zeta, 0.0)) {
+        return Some([[1, 0, -sign(eta)], [0, 1, 0], [0, 0, 1]]);
+    }
+    if gt(zeta.abs(), a) || (eq(zeta, a) && lt(2.0 * xi, eta)) || (eq(zeta, -a) && lt(eta, 0.0)) {
+        return Some([[1, -sign(zeta), 0], [0, 1, 0], [0, 0, 1]]);
+    }
+
+    // N8: body diagonal
+    let s = xi + eta + zeta + a + b;
+    if lt(s, 0.0) || (eq(s, 0.0) && gt(2.0 * (a + eta) + zeta, 0.0)) {
+        return Some([[1, 0, 1], [0, 1, 1], [0, 0, 1]]);
+    }
+    None
+}
+
+/// Niggli reduced cell and the matrix P with (a',b',c') = (a,b,c) P,
+/// where (a,b,c) is the primitive cell for the lattice type of the
+/// space group. The reduced cell carries no esu's and no space group
+pub fn reduce(cell: &Cell) -> (Cell, Matrix) {
+    let (a, b, c) = abc2vector(cell.a, cell.b, cell.c, cell.alpha, cell.beta, cell.gamma);
+    let abc = primitive([a, b, c], spacegroup::lattice(cell.sg));
+    let eps = EPSILON * volume(&abc[0], &abc[1], &abc[2]).abs().powf(2.0 / 3.0);
+    let mut p = IDENTITY;
+    for _ in 0..MAXCYCLES {
+        match step(&g6(&transform(&abc, &p)), eps) {
+            Some(m) => p = matmul(&p, &m),
+            None => break,
+        }
+    }
+
+    let reduced = Cell {
+        file: cell.file.clone(),
+        ..parameters(&transform(&abc, &p))
+    };
+    (reduced, p)
+}
+
+/// cell (a,b,c) P in the basis of the conventional cell, without
+/// esu's; file name, source, error model, statistics, scan and space
+/// group are kept
+pub fn transform_cell(cell: &Cell, p: &Matrix) -> Cell {
+    let (a, b, c) = abc2vector(cell.a, cell.b, cell.c, cell.alpha, cell.beta, cell.gamma);
+    Cell {
+        file: cell.file.clone(),
+        source: cell.source,
+        error_model: cell.error_model,
+        statistics: cell.statistics.clone(),
+        scan: cell.scan,
+        sg: cell.sg,
+        ..parameters(&transform(&[a, b, c], p))
+    }
+}
+
+// cell parameters of the basis vectors v
+fn parameters(v: &[XYZ; 3]) -> Cell {
+    let todeg = 180.0 / std::f64::consts::PI;
+    let g = g6(v);
+    let (la, lb, lc) = (g[0].sqrt(), g[1].sqrt(), g[2].sqrt());
+    Cell {
+        a: la,
+        b: lb,
+        c: lc,
+        alpha: todeg * f64::acos(0.5 * g[3] / (lb * lc)),
+        beta: todeg * f64::acos(0.5 * g[4] / (la * lc)),
+        gamma: todeg * f64::acos(0.5 * g[5] / (la * lb)),
+        ..Default::default()
+    }
+}
+
+/// new axes in terms of the old ones, e.g. "-b,-a,-c"
+pub fn basis(p: &Matrix) -> String {
+    let axis = |j: usize| -> String {
+        let mut s = String::new();
+        for (i, name) in ["a", "b", "c"].iter().enumerate() {
+            let x = p[i][j];
+            if x == 0 {
+                continue;
+            }
+            if x < 0 {
+                s += "-";
+            } else if !s.is_empty() {
+                s += "+";
+            }
+            if x.abs() != 1 {
+                s += &x.abs().to_string();
+            }
+            s += name;
+        }
+        s
+    };
+    format!("{},{},{}", axis(0), axis(1), axis(2))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cell(sg: i32, p: [f64; 6]) -> Cell {
+        Cell {
+            sg,
+            a: p[0],
+            b: p[1],
+            c: p[2],
+            alpha: p[3],
+            beta: p[4],
+            gamma: p[5],
+            ..Default::default()
+        }
+    }
+
+    fn assert_cell(c: &Cell, expected: [f64; 6]) {
+        let p = [c.a, c.b, c.c, c.alpha, c.beta, c.gamma];
+        for (x, y) in p.iter().zip(expected) {
+            assert!((x - y).abs() < 1.0e-3, "{p:?} != {expected:?}");
+        }
+    }
+
+    #[test]
+    fn triclinic_mixed_angles() {
+        // one acute angle: two axes are inverted to make all acute
+        let (reduced, p) = reduce(&cell(1, [10.0, 12.0, 15.0, 80.0, 95.0, 105.0]));
+        assert_cell(&reduced, [10.0, 12.0, 15.0, 80.0, 85.0, 75.0]);
+        assert_eq!(det(&p), 1);
+    }
+
+    #[test]
+    fn triclinic_reduced_is_kept() {
+        let (reduced, p) = reduce(&cell(1, [10.0, 12.0, 15.0, 80.0, 85.0, 75.0]));
+        assert_cell(&reduced, [10.0, 12.0, 15.0, 80.0, 85.0, 75.0]);
+        assert_eq!(p, IDENTITY);
+    }
+
+    #[test]
+    fn triclinic_from_other_basis() {
+        let reduced = cell(1, [10.0, 12.0, 15.0, 80.0, 85.0, 75.0]);
+        // b' = a + b, c' = a + c
+        let other = transform_cell(&reduced, &[[1, 1, 1], [0, 1, 0], [0, 0, 1]]);
+        let (again, p) = reduce(&other);
+        assert_cell(&again, [10.0, 12.0, 15.0, 80.0, 85.0, 75.0]);
+        assert_eq!(det(&p), 1);
+        assert_cell(
+            &transform_cell(&other, &p),
+            [10.0, 12.0, 15.0, 80.0, 85.0, 75.0],
+        );
+    }
+
+    #[test]
+    fn face_centred_cubic() {
+        // F23: primitive rhombohedral cell with a/sqrt(2) and 60 deg
+        let (reduced, _) = reduce(&cell(196, [10.0, 10.0, 10.0, 90.0, 90.0, 90.0]));
+        let a = 10.0 / f64::sqrt(2.0);
+        assert_cell(&reduced, [a, a, a, 60.0, 60.0, 60.0]);
+    }
+
+    #[test]
+    fn body_centred_cubic() {
+        // I23: a sqrt(3)/2 and the tetrahedral angle
+        let (reduced, _) = reduce(&cell(197, [10.0, 10.0, 10.0, 90.0, 90.0, 90.0]));
+        let a = 10.0 * f64::sqrt(3.0) / 2.0;
+        let angle = f64::acos(-1.0 / 3.0).to_degrees();
+        assert_cell(&reduced, [a, a, a, angle, angle, angle]);
+    }
+
+    #[test]
+    fn c_centred_monoclinic() {
+        // C2 with a = b: the primitive cell (a-b)/2, (a+b)/2, c has
+        // equal axes along the face diagonals
+        let (reduced, _) = reduce(&cell(5, [10.0, 10.0, 15.0, 90.0, 90.0, 90.0]));
+        let a = 10.0 / f64::sqrt(2.0);
+        assert_cell(&reduced, [a, a, 15.0, 90.0, 90.0, 90.0]);
+    }
+}
@@ -0,0 +1,511 @@
+//! CIF items for the pcf file: cells of the crystals, radiation and
+//! diffractometer geometry
+
+use crate::cell::{cell_volume, Cell, Source};
+use crate::hkl::resolution_ranges;
+use crate::niggli::{self, Matrix};
+use crate::scan::Scan;
+use crate::su;
+
+/// wavelengths below this limit (in A) are taken as electrons
+pub const ELECTRON_MAX_WAVELENGTH: f64 = 0.1;
+/// h*c in keV*A
+pub const HC: f64 = 12.398_42;
+/// electron rest energy in keV
+pub const MC2: f64 = 510.998_95;
+/// characteristic X-ray lines, wavelengths in A
+pub const XRAY_LINES: [(&str, f64); 7] = [
+    ("'Cu K\\a'", 1.54184),
+    ("'Mo K\\a'", 0.71073),
+    ("'Ag K\\a'", 0.56087),
+    ("'Ga K\\a'", 1.34143),
+    ("'In K\\a'", 0.51359),
+    ("'Co K\\a'", 1.79026),
+    ("'Cr K\\a'", 2.29100),
+];
+
+/// collection of experimental information for pcf-file; the items
+/// marked CORRECT.LP are read from the header of XDS_ASCII.HKL for
+/// source XdsAscii
+#[derive(Clone)]
+pub struct Pcf {
+    pub file: String,
+    pub source: Source,
+    pub num_refl: i32,                           // from CORRECT.LP only
+    pub detector: String,                        // from CORRECT.LP
+    pub nx: i32,                                 // from CORRECT.LP
+    pub ny: i32,                                 // from CORRECT.LP
+    pub qx: f64,                                 // from CORRECT.LP
+    pub qy: f64,                                 // from CORRECT.LP
+    pub distance: f64,                           // from CORRECT.LP
+    pub wavelength: f64,                         // from CORRECT.LP
+    pub cellabc: (f64, f64, f64, f64, f64, f64), // from CORRECT.LP (or Cell)
+    pub cellesd: (f64, f64, f64, f64, f64, f64), // from CORRECT.LP ( or Cell)
+    pub scan: Scan,                              // from CORRECT.LP or XDS.INP
+}
+
+impl Default for Pcf {
+    fn default() -> Pcf {
+        Pcf {
+            file: String::new(),
+            source: Source::CorrectLp,
+            num_refl: 0,
+            detector: String::new(),
+            nx: 0,
+            ny: 0,
+            qx: 0.0,
+            qy: 0.0,
+            distance: 0.0,
+            wavelength: 0.0,
+            cellabc: (0.0, 0.0, 0.0, 0.0, 0.0, 0.0),
+            cellesd: (0.0, 0.0, 0.0, 0.0, 0.0, 0.0),
+            scan: Scan::default(),
+        }
+    }
+}
+
+/// type of radiation, deduced from the wavelength
+#[derive(Clone, Copy, PartialEq)]
+pub enum Radiation {
+    Unknown,
+    XRay,
+    Electron,
+}
+
+/// radiation type for the wavelength in A
+pub fn radiation(wavelength: f64) -> Radiation {
+    if wavelength <= 0.0 {
+        Radiation::Unknown
+    } else if wavelength < ELECTRON_MAX_WAVELENGTH {
+        Radiation::Electron
+    } else {
+        Radiation::XRay
+    }
+}
+
+/// common radiation of all data sets, None if electron and
+/// X-ray data are mixed
+pub fn common_radiation(pcfs: &[Pcf]) -> Option<Radiation> {
+    let mut common = Radiation::Unknown;
+    for p in pcfs {
+        match (common, radiation(p.wavelength)) {
+            (_, Radiation::Unknown) => (),
+            (Radiation::Unknown, r) => common = r,
+            (c, r) if c != r => return None,
+            _ => (),
+        }
+    }
+    Some(common)
+}
+
+/// accelerating voltage in kV for relativistic electrons
+/// of the given wavelength
+pub fn electron_voltage(wavelength: f64) -> f64 {
+    let pc = HC / wavelength;
+    f64::sqrt(MC2 * MC2 + pc * pc) - MC2
+}
+
+/// cell for the theta range of each crystal in the pcf file
+#[derive(Clone, Copy, PartialEq)]
+pub enum ThetaCell {
+    /// the crystal's own cell, as in the same row of the loop
+    Own,
+    /// the weighted mean cell
+    Mean,
+}
+
+// cell of one crystal with its esu's, in space group sg
+fn crystal_cell(x: &Pcf, sg: i32) -> Cell {
+    let (a, b, c, al, be, ga) = x.cellesd;
+    Cell {
+        sg,
+        a: x.cellabc.0,
+        b: x.cellabc.1,
+        c: x.cellabc.2,
+        alpha: x.cellabc.3,
+        beta: x.cellabc.4,
+        gamma: x.cellabc.5,
+        a_esu: a,
+        b_esu: b,
+        c_esu: c,
+        alpha_esu: al,
+        beta_esu: be,
+        gamma_esu: ga,
+        ..Default::default()
+    }
+}
+
+/// write_pcf to pcffile
+pub fn write_pcf(
+    out: &mut String,
+    pcfs: Vec<Pcf>,
+    settings: &[Matrix],
+    mcell: &Cell,
+    theta_cell: ThetaCell,
+    pcffile: &str,
+    dataname: &str,
+) -> std::io::Result<()> {
+    let content = pcf_block(out, pcfs, settings, mcell, theta_cell, dataname);
+    std::fs::write(pcffile, content)
+}
+
+/// data block dataname of the pcf file with the weighted mean cell, the
+/// cells of the crystals and the experimental details of all data
+/// sets. settings are the transformations P of the data sets onto the
+/// setting of the mean cell, see reindex. The theta range of each
+/// crystal is computed with theta_cell, the overall range always with
+/// the mean cell, taken back into the setting of the data set.
+/// Warnings about unreadable XDS_ASCII.HKL files go to out
+pub fn pcf_block(
+    out: &mut String,
+    pcfs: Vec<Pcf>,
+    settings: &[Matrix],
+    mcell: &Cell,
+    theta_cell: ThetaCell,
+    dataname: &str,
+) -> String {
+    let (diffrn, looped) = diffrn_section(&pcfs);
+    let mut content = format!("data_{dataname}\n");
+    // weighted mean cell, used for refinement
+    let (vol, vol_esu) = cell_volume(mcell);
+    // the space group is unknown if no data set has one
+    let sg = if mcell.sg < 1 {
+        String::from("?")
+    } else {
+        mcell.sg.to_string()
+    };
+    content += &format!("_space_group_IT_number           {sg}\n");
+    content += &format!(
+        "_cell_length_a                   {}\n",
+        su::format(mcell.a, mcell.a_esu)
+    );
+    content += &format!(
+        "_cell_length_b                   {}\n",
+        su::format(mcell.b, mcell.b_esu)
+    );
+    content += &format!(
+        "_cell_length_c                   {}\n",
+        su::format(mcell.c, mcell.c_esu)
+    );
+    content += &format!(
+        "_cell_angle_alpha                {}\n",
+        su::format(mcell.alpha, mcell.alpha_esu)
+    );
+    content += &format!(
+        "_cell_angle_beta                 {}\n",
+        su::format(mcell.beta, mcell.beta_esu)
+    );
+    content += &format!(
+        "_cell_angle_gamma                {}\n",
+        su::format(mcell.gamma, mcell.gamma_esu)
+    );
+    content += &format!(
+        "_cell_volume                     {}\n",
+        su::format(vol, vol_esu)
+    );
+    // one loop over the crystals, with their cells under local names,
+    // since each data name may occur only once in the data block
+    let mut header = String::from("loop_\n");
+    header += &String::from("_exptl_crystal_id\n");
+    header += &String::from("_[local]_cell_length_a\n");
+    header += &String::from("_[local]_cell_length_b\n");
+    header += &String::from("_[local]_cell_length_c\n");
+    header += &String::from("_[local]_cell_angle_alpha\n");
+    header += &String::from("_[local]_cell_angle_beta\n");
+    header += &String::from("_[local]_cell_angle_gamma\n");
+    header += &String::from("_[local]_cell_volume\n");
+    header += &String::from("_[local]_cell_measurement_reflns_used\n");
+    header += &String::from("_[local]_cell_measurement_theta_min\n");
+    header += &String::from("_[local]_cell_measurement_theta_max\n");
+    let mut rows = String::new();
+    // the _diffrn_* items that differ between the crystals, in a loop
+    // of their own, since they do not belong to the crystal category
+    let mut diffrn_loop = String::new();
+    if !looped.is_empty() {
+        diffrn_loop += "\nloop_\n_[local]_diffrn_crystal_id\n";
+        for (tag, _) in &looped {
+            diffrn_loop += &format!("{tag}\n");
+        }
+        for id in 1..=pcfs.len() {
+            diffrn_loop += &format!("{id:-3}");
+            for (_, vals) in &looped {
+                diffrn_loop += &format!(" {}", vals[id - 1]);
+            }
+            diffrn_loop += "\n";
+        }
+    }
+    // the number of indexed spots is not known for data sets read
+    // from XDS_ASCII.HKL, the sum is then '?' as well
+    let mut global_refl_used: Option<i32> = Some(0);
+    let mut global_thetamin: f64 = 180.0;
+    let mut global_thetamax: f64 = 0.0;
+    // get dmin and dmax from XDS_ASCII.HKL, all files at once
+    let filenames: Vec<String> = pcfs
+        .iter()
+        .map(|x| {
+            let mut filename = x.file.clone();
+            if std::path::Path::new(&filename).is_dir() {
+                filename += "/CORRECT.LP";
+            }
+            filename.replace("CORRECT.LP", "XDS_ASCII.HKL")
+        })
+        .collect();
+    let cells: Vec<Cell> = pcfs.iter().map(|x| crystal_cell(x, mcell.sg)).collect();
+    // the indices in XDS_ASCII.HKL are those of the original setting
+    let means: Vec<Cell> = settings
+        .iter()
+        .map(|p| niggli::transform_cell(mcell, &niggli::inverse(p)))
+        .collect();
+    let jobs: Vec<(&str, Vec<&Cell>)> = filenames
+        .iter()
+        .zip(cells.iter().zip(&means))
+        .map(|(f, (c, m))| match theta_cell {
+            ThetaCell::Own => (f.as_str(), vec![c, m]),
+            ThetaCell::Mean => (f.as_str(), vec![m]),
+        })
+        .collect();
+    let ranges = resolution_ranges(&jobs);
+    for (((id, x), cell), range) in (1..).zip(pcfs).zip(&cells).zip(ranges) {
+        // theta range in degrees, '?' if XDS_ASCII.HKL cannot be read;
+        // the last range is the one of the mean cell
+        let todeg = 180.0 / std::f64::consts::PI;
+        let theta = |dstar: f64| todeg * f64::asin(f64::min(1.0, 0.5 * dstar * x.wavelength));
+        let (thetamin, thetamax) = match range {
+            Ok(r) => {
+                let (dstarmin, dstarmax) = r[r.len() - 1];
+                global_thetamin = f64::min(global_thetamin, theta(dstarmin));
+                global_thetamax = f64::max(global_thetamax, theta(dstarmax));
+                let (dstarmin, dstarmax) = r[0];
+                (
+                    format!("{:3.2}", theta(dstarmin)),
+                    format!("{:3.2}", theta(dstarmax)),
+                )
+            }
+            Err(e) => {
+                *out += &format!("! Warning: {e}, theta range unknown\n");
+                (String::from("?"), String::from("?"))
+            }
+        };
+        let refl_used = match x.source {
+            Source::CorrectLp => {
+                global_refl_used = global_refl_used.map(|n| n + x.num_refl);
+                x.num_refl.to_string()
+            }
+            Source::XdsAscii => {
+                global_refl_used = None;
+                String::from("?")
+            }
+        };
+        let (a, b, c, al, be, ga) = x.cellesd;
+        let (vol, vol_esu) = cell_volume(cell);
+        let s = format!(
+            "{id:-3} \
+		 {0} \
+		 {1} \
+		 {2} \
+		 {3} \
+		 {4} \
+		 {5} \
+		 {6} \
+		 {7:>6} \
+		 {8} \
+		 {9}\
+		 {10}\
+		\n",
+            su::format(x.cellabc.0, a),
+            su::format(x.cellabc.1, b),
+            su::format(x.cellabc.2, c),
+            su::format(x.cellabc.3, al),
+            su::format(x.cellabc.4, be),
+            su::format(x.cellabc.5, ga),
+            su::format(vol, vol_esu),
+            refl_used,
+            thetamin,
+            thetamax,
+            match x.source {
+                Source::CorrectLp => String::new(),
+                Source::XdsAscii => String::from(" # from XDS_ASCII.HKL header"),
+            }
+        );
+        rows += &s;
+    }
+    let s = format!(
+        "\n_cell_measurement_reflns_used    {}\n",
+        global_refl_used.map_or(String::from("?"), |n| n.to_string())
+    );
+    content += &s;
+    if global_thetamin <= global_thetamax {
+        let s = format!("_cell_measurement_theta_min      {:4.2}\n", global_thetamin);
+        content += &s;
+        let s = format!("_cell_measurement_theta_max      {:4.2}\n", global_thetamax);
+        content += &s;
+    } else {
+        content += "_cell_measurement_theta_min      ?\n";
+        content += "_cell_measurement_theta_max      ?\n";
+    }
+    content += &diffrn;
+    content += "\n";
+    content += &header;
+    content += &rows;
+    content += &diffrn_loop;
+    content
+}
+
+// _diffrn_* items from the detector and geometry data
+// of CORRECT.LP and the scan. Items that are identical for all
+// crystals are written once; the others are returned with their
+// values per crystal, for a loop over the crystals of their own
+#[allow(clippy::type_complexity)]
+fn diffrn_section(pcfs: &[Pcf]) -> (String, Vec<(&'static str, Vec<String>)>) {
+    // '?' marks values that could not be read from CORRECT.LP
+    let known = |x: f64, s: String| if x > 0.0 { s } else { String::from("?") };
+    let optional = |s: Option<String>| s.unwrap_or(String::from("?"));
+    let vector = |v: [f64; 3]| format!("'{:.6} {:.6} {:.6}'", v[0], v[1], v[2]);
+    let mut items: Vec<(&str, Vec<String>)> = vec![
+        (
+            "_diffrn_radiation_probe",
+            pcfs.iter()
+                .map(|p| match radiation(p.wavelength) {
+                    Radiation::Electron => String::from("electron"),
+                    Radiation::XRay => String::from("x-ray"),
+                    Radiation::Unknown => String::from("?"),
+                })
+                .collect(),
+        ),
+        (
+            "_diffrn_radiation_type",
+            pcfs.iter().map(|p| radiation_type(p.wavelength)).collect(),
+        ),
+        (
+            "_diffrn_radiation_wavelength",
+            pcfs.iter()
+                .map(|p| known(p.wavelength, format!("{:.5}", p.wavelength)))
+                .collect(),
+        ),
+        (
+            "_diffrn_detector_type",
+            pcfs.iter()
+                .map(|p| {
+                    if p.detector.is_empty() {
+                        String::from("?")
+                    } else {
+                        format!("'{}'", p.detector)
+                    }
+                })
+                .collect(),
+        ),
+        (
+            "_diffrn_detector_area_resol_mean",
+            pcfs.iter()
+                .map(|p| known(p.qx, format!("{:.3}", 1.0 / p.qx)))
+                .collect(),
+        ),
+        (
+            "_[local]_diffrn_detector_pixel_size",
+            pcfs.iter()
+                .map(|p| known(p.qx * p.qy, format!("'{:.4} x {:.4}'", p.qx, p.qy)))
+                .collect(),
+        ),
+        (
+            "_[local]_diffrn_detector_area",
+            pcfs.iter()
+                .map(|p| {
+                    let (x, y) = (p.nx as f64 * p.qx, p.ny as f64 * p.qy);
+                    known(x * y, format!("'{x:.1} x {y:.1}'"))
+                })
+                .collect(),
+        ),
+        (
+            "_[local]_diffrn_detector_distance",
+            pcfs.iter()
+                .map(|p| known(p.distance, format!("{:.2}", p.distance)))
+                .collect(),
+        ),
+        (
+            "_diffrn_measurement_method",
+            pcfs.iter()
+                .map(|p| match p.scan.oscillation_range {
+                    Some(_) => String::from("'rotation method'"),
+                    None => String::from("?"),
+                })
+                .collect(),
+        ),
+        (
+            "_diffrn_measurement_details",
+            pcfs.iter()
+                .map(|p| {
+                    p.scan
+                        .describe()
+                        .map_or(String::from("?"), |s| format!("'{s}'"))
+                })
+                .collect(),
+        ),
+        (
+            "_[local]_diffrn_scan_width",
+            pcfs.iter()
+                .map(|p| optional(p.scan.oscillation_range.map(|x| format!("{x:.4}"))))
+                .collect(),
+        ),
+        (
+            "_[local]_diffrn_scan_frames",
+            pcfs.iter()
+                .map(|p| optional(p.scan.frames().map(|n| n.to_string())))
+                .collect(),
+        ),
+        (
+            "_[local]_diffrn_scan_total_rotation",
+            pcfs.iter()
+                .map(|p| optional(p.scan.total_rotation().map(|x| format!("{x:.2}"))))
+                .collect(),
+        ),
+        (
+            "_[local]_diffrn_rotation_axis",
+            pcfs.iter()
+                .map(|p| optional(p.scan.rotation_axis.map(vector)))
+                .collect(),
+        ),
+        (
+            "_[local]_diffrn_incident_beam_direction",
+            pcfs.iter()
+                .map(|p| optional(p.scan.incident_beam_direction.map(vector)))
+                .collect(),
+        ),
+    ];
+    if common_radiation(pcfs) == Some(Radiation::Electron) {
+        items.push((
+            "_diffrn_source_voltage",
+            pcfs.iter()
+                .map(|p| format!("{:.0}", electron_voltage(p.wavelength)))
+                .collect(),
+        ));
+    }
+
+    let mut global = String::from("\n");
+    let mut looped: Vec<(&str, Vec<String>)> = Vec::new();
+    for item in items {
+        let (tag, vals) = &item;
+        if vals.iter().all(|v| *v == vals[0]) {
+            global += &format!("{:-36} {}\n", tag, vals[0]);
+        } else {
+            looped.push(item);
+        }
+    }
+    (global, looped)
+}
+
+// _diffrn_radiation_type: electrons, characteristic X-ray
+// lines, or synchrotron radiation otherwise
+fn radiation_type(wavelength: f64) -> String {
+    match radiation(wavelength) {
+        Radiation::Unknown => String::from("?"),
+        Radiation::Electron => String::from("electron"),
+        Radiation::XRay => {
+            for (line, lambda) in XRAY_LINES {
+                if f64::abs(wavelength - lambda) < 0.0005 {
+                    return String::from(line);
+                }
+            }
+            String::from("synchrotron")
+        }
+    }
+}
@@ -0,0 +1,363 @@
+//! Reindexing of data sets onto the setting of a reference data set.
+//! Candidates are unimodular matrices with elements -1, 0, 1 which
+//! keep the lattice centring; the one whose cell is closest to the
+//! reference cell (G6 distance) wins. Candidates that fit equally well
+//! but do not differ by a rotation of the Laue group are alternative
+//! indexings; they are ranked by the correlation of the intensities
+//! with those of the reference data set
+
+use std::collections::HashMap;
+
+use crate::cluster;
+use crate::correct::ParseError;
+use crate::hkl::Reflections;
+use crate::niggli::{self, Matrix, IDENTITY};
+use crate::spacegroup::{self, Laue};
+use crate::Cell;
+
+// candidates within this G6 distance (%) of the best one are
+// considered as alternative indexings
+const AMBIGUITY: f64 = 0.5;
+
+// minimum number of common unique reflections for a correlation
+const MIN_COMMON: usize = 10;
+
+// transformed esu's below this are due to rounding only
+const MIN_ESU: f64 = 1.0e-4;
+
+/// transformation of one data set onto the reference setting;
+/// alternatives are the other indexings with their correlation
+/// to the reference
+#[derive(Clone)]
+pub struct Reindexing {
+    pub p: Matrix,
+    pub alternatives: Vec<(Matrix, Option<f64>)>,
+}
+
+impl Reindexing {
+    /// data set already in the reference setting
+    pub fn identity() -> Reindexing {
+        Reindexing {
+            p: IDENTITY,
+            alternatives: Vec::new(),
+        }
+    }
+}
+
+/// REIDX of XSCALE.INP for (a',b',c') = (a,b,c) P: the new indices are
+/// (h',k',l') = (h,k,l) P, i.e. the rows of REIDX are the columns of P
+pub fn reidx(p: &Matrix) -> String {
+    let columns: String = (0..3)
+        .map(|j| format!(" {:2} {:2} {:2}  0", p[0][j], p[1][j], p[2][j]))
+        .collect();
+    format!("REIDX={columns}")
+}
+
+/// rotations of the Laue group in the conventional setting, as
+/// matrices acting on the basis vectors
+pub fn rotations(laue: Laue) -> Vec<Matrix> {
+    let two_y = [[-1, 0, 0], [0, 1, 0], [0, 0, -1]];
+    let two_z = [[-1, 0, 0], [0, -1, 0], [0, 0, 1]];
+    let two_x = [[1, 0, 0], [0, -1, 0], [0, 0, -1]];
+    let two_110 = [[0, 1, 0], [1, 0, 0], [0, 0, -1]];
+    let two_1m10 = [[0, -1, 0], [-1, 0, 0], [0, 0, -1]];
+    let four_z = [[0, -1, 0], [1, 0, 0], [0, 0, 1]];
+    let three_z = [[0, -1, 0], [1, -1, 0], [0, 0, 1]];
+    let six_z = [[1, -1, 0], [1, 0, 0], [0, 0, 1]];
+    let three_111 = [[0, 0, 1], [1, 0, 0], [0, 1, 0]];
+    let generators = match laue {
+        Laue::Triclinic => vec![],
+        Laue::Monoclinic => vec![two_y],
+        Laue::Orthorhombic => vec![two_z, two_x],
+        Laue::Tetragonal4m => vec![four_z],
+        Laue::Tetragonal4mmm => vec![four_z, two_x],
+        Laue::Trigonal3 => vec![three_z],
+        Laue::Trigonal3m1 => vec![three_z, two_110],
+        Laue::Trigonal31m => vec![three_z, two_1m10],
+        Laue::Hexagonal6m => vec![six_z],
+        Laue::Hexagonal6mmm => vec![six_z, two_110],
+        Laue::Cubicm3 => vec![two_z, two_x, three_111],
+        Laue::Cubicm3m => vec![four_z, three_111],
+    };
+
+    // closure under multiplication
+    let mut group = vec![IDENTITY];
+    let mut n = 0;
+    while n < group.len() {
+        for g in &generators {
+            let r = niggli::matmul(&group[n], g);
+            if !group.contains(&r) {
+                group.push(r);
+            }
+        }
+        n += 1;
+    }
+    group
+}
+
+// centring translations in units of 1/6
+fn centring(lattice: char) -> Vec<[i32; 3]> {
+    match lattice {
+        'A' => vec![[0, 3, 3]],
+        'B' => vec![[3, 0, 3]],
+        'C' => vec![[3, 3, 0]],
+        'I' => vec![[3, 3, 3]],
+        'F' => vec![[0, 3, 3], [3, 0, 3], [3, 3, 0]],
+        'R' => vec![[4, 2, 2], [2, 4, 4]],
+        _ => vec![],
+    }
+}
+
+// unimodular matrices with elements -1, 0, 1 which map the centring
+// translations onto themselves
+fn candidates(lattice: char) -> Vec<Matrix> {
+    let translations = centring(lattice);
+    let mut list = Vec::new();
+    for n in 0..3_i32.pow(9) {
+        let mut p = [[0; 3]; 3];
+        let mut m = n;
+        for row in p.iter_mut() {
+            for x in row.iter_mut() {
+                *x = m % 3 - 1;
+                m /= 3;
+            }
+        }
+        if niggli::det(&p) != 1 {
+            continue;
+        }
+        // fractional coordinates transform with the inverse
+        let q = niggli::inverse(&p);
+        let keeps = translations.iter().all(|t| {
+            let mut u = [0; 3];
+            for (i, x) in u.iter_mut().enumerate() {
+                *x = (q[i][0] * t[0] + q[i][1] * t[1] + q[i][2] * t[2]).rem_euclid(6);
+            }
+            translations.contains(&u)
+        });
+        if keeps {
+            list.push(p);
+        }
+    }
+    list
+}
+
+/// cell and esu's after the transformation P; esu's are propagated
+/// numerically from the original ones
+pub fn apply(cell: &Cell, p: &Matrix) -> Cell {
+    if *p == IDENTITY {
+        return cell.clone();
+    }
+    let params = |c: &Cell| [c.a, c.b, c.c, c.alpha, c.beta, c.gamma];
+    let esus = [
+        cell.a_esu,
+        cell.b_esu,
+        cell.c_esu,
+        cell.alpha_esu,
+        cell.beta_esu,
+        cell.gamma_esu,
+    ];
+    let new = niggli::transform_cell(cell, p);
+    if esus.iter().all(|e| *e < 0.0) {
+        return new;
+    }
+    let x0 = params(&new);
+    let mut var = [0.0_f64; 6];
+    for (j, esu) in esus.iter().enumerate().filter(|(_, e)| **e > 0.0) {
+        let mut x = params(cell);
+        x[j] += esu;
+        let shifted = Cell {
+            a: x[0],
+            b: x[1],
+            c: x[2],
+            alpha: x[3],
+            beta: x[4],
+            gamma: x[5],
+            ..cell.clone()
+        };
+        let x1 = params(&niggli::transform_cell(&shifted, p));
+        for k in 0..6 {
+            var[k] += (x1[k] - x0[k]).powi(2);
+        }
+    }
+    let su = var.map(|v| if v.sqrt() < MIN_ESU { 0.0 } else { v.sqrt() });
+    Cell {
+        a_esu: su[0],
+        b_esu: su[1],
+        c_esu: su[2],
+        alpha_esu: su[3],
+        beta_esu: su[4],
+        gamma_esu: su[5],
+        ..new
+    }
+}
+
+/// find the transformations of all data sets onto the setting of
+/// `cells[0]` for space group sg. Prints a report
+pub fn reindex(out: &mut String, cells: &[Cell], sg: i32) -> Vec<Reindexing> {
+    let laue = match spacegroup::laue(sg) {
+        Some(laue) => laue,
+        None => return vec![Reindexing::identity(); cells.len()],
+    };
+    let group = rotations(laue);
+    let list = candidates(spacegroup::lattice(sg));
+    let reference = &cells[0];
+    let mut refdata: Option<HashMap<[i32; 3], f64>> = None;
+
+    *out += &format!(
+        "! Reindexing onto the setting of {}, Laue class {}:\n",
+        reference.file,
+        laue.symbol()
+    );
+    *out += "!   dist/%  (a',b',c')            CC  data set\n";
+    let mut result = vec![Reindexing::identity()];
+    for cell in cells.iter().skip(1) {
+        // cells that cannot be set up, e.g. with impossible angles,
+        // give no distance
+        let mut fits: Vec<(f64, Matrix)> = list
+            .iter()
+            .map(|p| {
+                (
+                    cluster::distance(&niggli::transform_cell(cell, p), reference),
+                    *p,
+                )
+            })
+            .filter(|(d, _)| d.is_finite())
+            .collect();
+        fits.sort_by(|x, y| x.0.total_cmp(&y.0));
+        let Some(&(best, _)) = fits.first() else {
+            *out += &format!(
+                "! {:>8}  {:20}{:>6}  {} (no fit to the reference cell, kept as is)\n",
+                "?",
+                niggli::basis(&IDENTITY),
+                "-",
+                cell.file
+            );
+            result.push(Reindexing::identity());
+            continue;
+        };
+
+        // one representative per coset of the Laue group, the one
+        // closest to the identity first
+        let mut indexings: Vec<Matrix> = Vec::new();
+        for (_, p) in fits.iter().take_while(|(d, _)| *d <= best + AMBIGUITY) {
+            let pinv = niggli::inverse(p);
+            match indexings
+                .iter()
+                .position(|q| group.contains(&niggli::matmul(&pinv, q)))
+            {
+                Some(n) if trace(p) > trace(&indexings[n]) => indexings[n] = *p,
+                Some(_) => (),
+                None => indexings.push(*p),
+            }
+        }
+
+        let mut ranked: Vec<(Matrix, Option<f64>)> = indexings.iter().map(|p| (*p, None)).collect();
+        if ranked.len() > 1 {
+            // without intensities, the indexing closest to the
+            // identity is taken
+            let mut read = |cell: &Cell, p: &Matrix| {
+                intensities(cell, p, &group).unwrap_or_else(|e| {
+                    *out += &format!("! Warning: {e}, cannot choose the indexing\n");
+                    HashMap::new()
+                })
+            };
+            let refdata = refdata.get_or_insert_with(|| read(reference, &IDENTITY));
+            for (p, cc) in ranked.iter_mut() {
+                *cc = correlation(refdata, &read(cell, p));
+            }
+            ranked.sort_by(|x, y| y.1.unwrap_or(-1.0).total_cmp(&x.1.unwrap_or(-1.0)));
+        }
+        let (p, cc) = ranked.remove(0);
+
+        let ccstr = |cc: Option<f64>| match cc {
+            Some(cc) => format!("{cc:6.3}"),
+            None => String::from("     -"),
+        };
+        *out += &format!(
+            "! {:8.2}  {:20}{}  {}\n",
+            cluster::distance(&niggli::transform_cell(cell, &p), reference),
+            niggli::basis(&p),
+            ccstr(cc),
+            cell.file
+        );
+        for (q, cc) in &ranked {
+            *out += &format!(
+                "! {:8}  {:20}{}  alternative indexing\n",
+                "",
+                niggli::basis(q),
+                ccstr(*cc)
+            );
+        }
+        result.push(Reindexing {
+            p,
+            alternatives: ranked,
+        });
+    }
+    *out += "!\n";
+    result
+}
+
+fn trace(p: &Matrix) -> i32 {
+    p[0][0] + p[1][1] + p[2][2]
+}
+
+// mean intensities of the unique reflections of XDS_ASCII.HKL next
+// to the CORRECT.LP of cell, after reindexing with P. Friedel mates
+// and symmetry equivalents are merged
+fn intensities(
+    cell: &Cell,
+    p: &Matrix,
+    group: &[Matrix],
+) -> Result<HashMap<[i32; 3], f64>, ParseError> {
+    let xdsascii = cell.file.replace("CORRECT.LP", "XDS_ASCII.HKL");
+    let mut sums: HashMap<[i32; 3], (f64, usize)> = HashMap::new();
+    let reflections = Reflections::open(&xdsascii)?;
+    // unreadable records are skipped
+    for r in reflections.flatten() {
+        // XDS marks misfits with negative sigma
+        if r.sigma <= 0.0 {
+            continue;
+        }
+        let hkl = r.hkl;
+        let h: [i32; 3] = std::array::from_fn(|j| (0..3).map(|i| hkl[i] * p[i][j]).sum());
+        let entry = sums.entry(unique(&h, group)).or_insert((0.0, 0));
+        entry.0 += r.iobs;
+        entry.1 += 1;
+    }
+    Ok(sums
+        .into_iter()
+        .map(|(h, (s, n))| (h, s / n as f64))
+        .collect())
+}
+
+// representative of the symmetry equivalents and Friedel mates of h;
+// indices transform with the transpose of the rotations
+fn unique(h: &[i32; 3], group: &[Matrix]) -> [i32; 3] {
+    let mut best = *h;
+    for r in group {
+        let g: [i32; 3] = std::array::from_fn(|j| (0..3).map(|i| h[i] * r[i][j]).sum());
+        best = best.max(g).max(g.map(|x| -x));
+    }
+    best
+}
+
+// Pearson correlation over common reflections
+fn correlation(x: &HashMap<[i32; 3], f64>, y: &HashMap<[i32; 3], f64>) -> Option<f64> {
+    let pairs: Vec<(f64, f64)> = x
+        .iter()
+        .filter_map(|(h, i)| y.get(h).map(|j| (*i, *j)))
+        .collect();
+    if pairs.len() < MIN_COMMON {
+        return None;
+    }
+    let n = pairs.len() as f64;
+    let mx = pairs.iter().map(|p| p.0).sum::<f64>() / n;
+    let my = pairs.iter().map(|p| p.1).sum::<f64>() / n;
+    let sxy: f64 = pairs.iter().map(|p| (p.0 - mx) * (p.1 - my)).sum();
+    let sxx: f64 = pairs.iter().map(|p| (p.0 - mx).powi(2)).sum();
+    let syy: f64 = pairs.iter().map(|p| (p.1 - my).powi(2)).sum();
+    if sxx <= 0.0 || syy <= 0.0 {
+        return None;
+    }
+    Some(sxy / f64::sqrt(sxx * syy))
+}
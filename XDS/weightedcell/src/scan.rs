@@ -0,0 +1,131 @@
+//! Measurement strategy of a data set: rotation per frame, frames and
+//! geometry, from the parameter echo of CORRECT.LP, the header of
+//! XDS_ASCII.HKL or XDS.INP
+
+use crate::correct::ParseError;
+
+/// rotation scan of a data set; items that were not found are None
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Scan {
+    /// rotation per frame in degrees
+    pub oscillation_range: Option<f64>,
+    /// first and last frame
+    pub data_range: Option<(i32, i32)>,
+    pub starting_angle: Option<f64>,
+    pub rotation_axis: Option<[f64; 3]>,
+    pub incident_beam_direction: Option<[f64; 3]>,
+    pub friedels_law: Option<bool>,
+}
+
+impl Scan {
+    /// read the keywords of one line in the syntax of XDS.INP, i.e.
+    /// KEYWORD= values, possibly several per line and with comments
+    /// after '!'. Items that are already set are kept
+    pub fn read_line(&mut self, l: &str) {
+        let l = l.split('!').next().unwrap_or_default();
+        if !l.contains('=') {
+            return;
+        }
+        let mut keyword = "";
+        let mut values: Vec<&str> = Vec::new();
+        for w in l.split_whitespace() {
+            match w.split_once('=') {
+                Some((k, v)) => {
+                    self.set(keyword, &values);
+                    keyword = k;
+                    values.clear();
+                    if !v.is_empty() {
+                        values.push(v);
+                    }
+                }
+                None => values.push(w),
+            }
+        }
+        self.set(keyword, &values);
+    }
+
+    // set the item of keyword unless it is set already; values that
+    // cannot be read are ignored
+    fn set(&mut self, keyword: &str, values: &[&str]) {
+        let x: Vec<f64> = values.iter().map_while(|v| v.parse().ok()).collect();
+        let vector = || -> Option<[f64; 3]> { x.get(0..3).map(|v| [v[0], v[1], v[2]]) };
+        match keyword {
+            "OSCILLATION_RANGE" => {
+                self.oscillation_range = self.oscillation_range.or(x.first().copied())
+            }
+            "DATA_RANGE" => {
+                let range = match x[..] {
+                    [first, last, ..] => Some((first as i32, last as i32)),
+                    _ => None,
+                };
+                self.data_range = self.data_range.or(range);
+            }
+            "STARTING_ANGLE" => self.starting_angle = self.starting_angle.or(x.first().copied()),
+            "ROTATION_AXIS" => self.rotation_axis = self.rotation_axis.or(vector()),
+            "INCIDENT_BEAM_DIRECTION" => {
+                self.incident_beam_direction = self.incident_beam_direction.or(vector())
+            }
+            "FRIEDEL'S_LAW" => {
+                let law = match values.first() {
+                    Some(&"TRUE") => Some(true),
+                    Some(&"FALSE") => Some(false),
+                    _ => None,
+                };
+                self.friedels_law = self.friedels_law.or(law);
+            }
+            _ => (),
+        }
+    }
+
+    /// items that are not set are taken from other
+    pub fn or(self, other: Scan) -> Scan {
+        Scan {
+            oscillation_range: self.oscillation_range.or(other.oscillation_range),
+            data_range: self.data_range.or(other.data_range),
+            starting_angle: self.starting_angle.or(other.starting_angle),
+            rotation_axis: self.rotation_axis.or(other.rotation_axis),
+            incident_beam_direction: self
+                .incident_beam_direction
+                .or(other.incident_beam_direction),
+            friedels_law: self.friedels_law.or(other.friedels_law),
+        }
+    }
+
+    /// number of frames
+    pub fn frames(&self) -> Option<i32> {
+        self.data_range.map(|(first, last)| last - first + 1)
+    }
+
+    /// total rotation in degrees
+    pub fn total_rotation(&self) -> Option<f64> {
+        Some(self.frames()? as f64 * self.oscillation_range?)
+    }
+
+    /// short description, e.g. "900 frames of 0.100 deg, 90.00 deg
+    /// from 0.000 deg"; None if rotation or frames are unknown
+    pub fn describe(&self) -> Option<String> {
+        let mut s = format!(
+            "{} frames of {:.3} deg, {:.2} deg",
+            self.frames()?,
+            self.oscillation_range?,
+            self.total_rotation()?
+        );
+        if let Some(start) = self.starting_angle {
+            s += &format!(" from {start:.3} deg");
+        }
+        Some(s)
+    }
+}
+
+/// scan from XDS.INP
+pub fn rd_xdsinp(filename: &str) -> Result<Scan, ParseError> {
+    let xdsinp = std::fs::read_to_string(filename).map_err(|error| ParseError::Io {
+        file: filename.to_string(),
+        error,
+    })?;
+    let mut scan = Scan::default();
+    for l in xdsinp.lines() {
+        scan.read_line(l);
+    }
+    Ok(scan)
+}
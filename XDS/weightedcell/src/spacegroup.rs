@@ -0,0 +1,305 @@
+//! Space group tables: Hermann-Mauguin symbols, Laue classes and
+//! lattice types of the 230 space groups in their standard
+//! settings, as used by XDS
+
+#[rustfmt::skip]
+const SYMBOLS: [&str; 230] = [
+    "P1", "P-1", "P2", "P21", "C2", "Pm", "Pc", "Cm", "Cc", "P2/m", // 1-10
+    "P21/m", "C2/m", "P2/c", "P21/c", "C2/c", "P222", "P2221", "P21212", "P212121", "C2221", // 11-20
+    "C222", "F222", "I222", "I212121", "Pmm2", "Pmc21", "Pcc2", "Pma2", "Pca21", "Pnc2", // 21-30
+    "Pmn21", "Pba2", "Pna21", "Pnn2", "Cmm2", "Cmc21", "Ccc2", "Amm2", "Aem2", "Ama2", // 31-40
+    "Aea2", "Fmm2", "Fdd2", "Imm2", "Iba2", "Ima2", "Pmmm", "Pnnn", "Pccm", "Pban", // 41-50
+    "Pmma", "Pnna", "Pmna", "Pcca", "Pbam", "Pccn", "Pbcm", "Pnnm", "Pmmn", "Pbcn", // 51-60
+    "Pbca", "Pnma", "Cmcm", "Cmce", "Cmmm", "Cccm", "Cmme", "Ccce", "Fmmm", "Fddd", // 61-70
+    "Immm", "Ibam", "Ibca", "Imma", "P4", "P41", "P42", "P43", "I4", "I41", // 71-80
+    "P-4", "I-4", "P4/m", "P42/m", "P4/n", "P42/n", "I4/m", "I41/a", "P422", "P4212", // 81-90
+    "P4122", "P41212", "P4222", "P42212", "P4322", "P43212", "I422", "I4122", "P4mm", "P4bm", // 91-100
+    "P42cm", "P42nm", "P4cc", "P4nc", "P42mc", "P42bc", "I4mm", "I4cm", "I41md", "I41cd", // 101-110
+    "P-42m", "P-42c", "P-421m", "P-421c", "P-4m2", "P-4c2", "P-4b2", "P-4n2", "I-4m2", "I-4c2", // 111-120
+    "I-42m", "I-42d", "P4/mmm", "P4/mcc", "P4/nbm", "P4/nnc", "P4/mbm", "P4/mnc", "P4/nmm", "P4/ncc", // 121-130
+    "P42/mmc", "P42/mcm", "P42/nbc", "P42/nnm", "P42/mbc", "P42/mnm", "P42/nmc", "P42/ncm", "I4/mmm", "I4/mcm", // 131-140
+    "I41/amd", "I41/acd", "P3", "P31", "P32", "R3", "P-3", "R-3", "P312", "P321", // 141-150
+    "P3112", "P3121", "P3212", "P3221", "R32", "P3m1", "P31m", "P3c1", "P31c", "R3m", // 151-160
+    "R3c", "P-31m", "P-31c", "P-3m1", "P-3c1", "R-3m", "R-3c", "P6", "P61", "P65", // 161-170
+    "P62", "P64", "P63", "P-6", "P6/m", "P63/m", "P622", "P6122", "P6522", "P6222", // 171-180
+    "P6422", "P6322", "P6mm", "P6cc", "P63cm", "P63mc", "P-6m2", "P-6c2", "P-62m", "P-62c", // 181-190
+    "P6/mmm", "P6/mcc", "P63/mcm", "P63/mmc", "P23", "F23", "I23", "P213", "I213", "Pm-3", // 191-200
+    "Pn-3", "Fm-3", "Fd-3", "Im-3", "Pa-3", "Ia-3", "P432", "P4232", "F432", "F4132", // 201-210
+    "I432", "P4332", "P4132", "I4132", "P-43m", "F-43m", "I-43m", "P-43n", "F-43c", "I-43d", // 211-220
+    "Pm-3m", "Pn-3n", "Pm-3n", "Pn-3m", "Fm-3m", "Fm-3c", "Fd-3m", "Fd-3c", "Im-3m", "Ia-3d", // 221-230
+];
+
+/// Laue classes; the trigonal classes -3m1 and -31m differ
+/// by the orientation of the twofold axes
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Laue {
+    Triclinic,
+    Monoclinic,
+    Orthorhombic,
+    Tetragonal4m,
+    Tetragonal4mmm,
+    Trigonal3,
+    Trigonal3m1,
+    Trigonal31m,
+    Hexagonal6m,
+    Hexagonal6mmm,
+    Cubicm3,
+    Cubicm3m,
+}
+
+/// crystal systems with respect to the constraints on the cell
+/// parameters; trigonal and rhombohedral cells are in the
+/// hexagonal setting
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum CrystalSystem {
+    Triclinic,
+    Monoclinic,
+    Orthorhombic,
+    Tetragonal,
+    Hexagonal,
+    Cubic,
+}
+
+impl Laue {
+    /// Hermann-Mauguin symbol of the Laue class
+    pub fn symbol(&self) -> &'static str {
+        match self {
+            Laue::Triclinic => "-1",
+            Laue::Monoclinic => "2/m",
+            Laue::Orthorhombic => "mmm",
+            Laue::Tetragonal4m => "4/m",
+            Laue::Tetragonal4mmm => "4/mmm",
+            Laue::Trigonal3 => "-3",
+            Laue::Trigonal3m1 => "-3m1",
+            Laue::Trigonal31m => "-31m",
+            Laue::Hexagonal6m => "6/m",
+            Laue::Hexagonal6mmm => "6/mmm",
+            Laue::Cubicm3 => "m-3",
+            Laue::Cubicm3m => "m-3m",
+        }
+    }
+
+    /// crystal system with respect to the cell constraints
+    pub fn system(&self) -> CrystalSystem {
+        match self {
+            Laue::Triclinic => CrystalSystem::Triclinic,
+            Laue::Monoclinic => CrystalSystem::Monoclinic,
+            Laue::Orthorhombic => CrystalSystem::Orthorhombic,
+            Laue::Tetragonal4m | Laue::Tetragonal4mmm => CrystalSystem::Tetragonal,
+            Laue::Trigonal3
+            | Laue::Trigonal3m1
+            | Laue::Trigonal31m
+            | Laue::Hexagonal6m
+            | Laue::Hexagonal6mmm => CrystalSystem::Hexagonal,
+            Laue::Cubicm3 | Laue::Cubicm3m => CrystalSystem::Cubic,
+        }
+    }
+
+    /// number of symmetry operations of the point group
+    pub fn order(&self) -> i32 {
+        match self {
+            Laue::Triclinic => 2,
+            Laue::Monoclinic => 4,
+            Laue::Orthorhombic => 8,
+            Laue::Tetragonal4m => 8,
+            Laue::Tetragonal4mmm => 16,
+            Laue::Trigonal3 => 6,
+            Laue::Trigonal3m1 | Laue::Trigonal31m => 12,
+            Laue::Hexagonal6m => 12,
+            Laue::Hexagonal6mmm => 24,
+            Laue::Cubicm3 => 24,
+            Laue::Cubicm3m => 48,
+        }
+    }
+
+    /// subgroups (including itself) which keep the axes of the
+    /// standard setting, i.e. which can be described with the
+    /// same unit cell
+    pub fn subgroups(&self) -> Vec<Laue> {
+        let mut sub = match self {
+            Laue::Triclinic => vec![],
+            Laue::Monoclinic => vec![Laue::Triclinic],
+            Laue::Orthorhombic => vec![Laue::Monoclinic, Laue::Triclinic],
+            Laue::Tetragonal4m => vec![Laue::Triclinic],
+            Laue::Tetragonal4mmm => vec![
+                Laue::Tetragonal4m,
+                Laue::Orthorhombic,
+                Laue::Monoclinic,
+                Laue::Triclinic,
+            ],
+            Laue::Trigonal3 => vec![Laue::Triclinic],
+            Laue::Trigonal3m1 | Laue::Trigonal31m => vec![Laue::Trigonal3, Laue::Triclinic],
+            Laue::Hexagonal6m => vec![Laue::Trigonal3, Laue::Triclinic],
+            Laue::Hexagonal6mmm => vec![
+                Laue::Hexagonal6m,
+                Laue::Trigonal3m1,
+                Laue::Trigonal31m,
+                Laue::Trigonal3,
+                Laue::Triclinic,
+            ],
+            Laue::Cubicm3 => vec![Laue::Orthorhombic, Laue::Monoclinic, Laue::Triclinic],
+            Laue::Cubicm3m => vec![
+                Laue::Cubicm3,
+                Laue::Tetragonal4mmm,
+                Laue::Tetragonal4m,
+                Laue::Orthorhombic,
+                Laue::Monoclinic,
+                Laue::Triclinic,
+            ],
+        };
+        sub.insert(0, *self);
+        sub
+    }
+}
+
+/// Hermann-Mauguin symbol of space group number sg
+pub fn symbol(sg: i32) -> &'static str {
+    match sg {
+        1..=230 => SYMBOLS[sg as usize - 1],
+        _ => "?",
+    }
+}
+
+/// Laue class of space group sg, None if sg is not a valid number
+pub fn laue(sg: i32) -> Option<Laue> {
+    let l = match sg {
+        1..=2 => Laue::Triclinic,
+        3..=15 => Laue::Monoclinic,
+        16..=74 => Laue::Orthorhombic,
+        75..=88 => Laue::Tetragonal4m,
+        89..=142 => Laue::Tetragonal4mmm,
+        143..=148 => Laue::Trigonal3,
+        149 | 151 | 153 | 157 | 159 | 162 | 163 => Laue::Trigonal31m,
+        150..=167 => Laue::Trigonal3m1,
+        168..=176 => Laue::Hexagonal6m,
+        177..=194 => Laue::Hexagonal6mmm,
+        195..=206 => Laue::Cubicm3,
+        207..=230 => Laue::Cubicm3m,
+        _ => return None,
+    };
+    Some(l)
+}
+
+/// lattice type, i.e. the first letter of the symbol
+pub fn lattice(sg: i32) -> char {
+    symbol(sg).chars().next().unwrap_or('?')
+}
+
+/// true for the centrosymmetric space groups
+pub fn centrosymmetric(sg: i32) -> bool {
+    matches!(
+        sg,
+        2 | 10..=15
+            | 47..=74
+            | 83..=88
+            | 123..=142
+            | 147..=148
+            | 162..=167
+            | 175..=176
+            | 191..=194
+            | 200..=206
+            | 221..=230
+    )
+}
+
+// centring translations as bit set: A, B, C, I, R(obverse)
+fn centring(lattice: char) -> u8 {
+    match lattice {
+        'A' => 1,
+        'B' => 2,
+        'C' => 4,
+        'F' => 7,
+        'I' => 8,
+        'R' => 16,
+        _ => 0,
+    }
+}
+
+fn lattice_from_centring(c: u8) -> char {
+    match c {
+        1 => 'A',
+        2 => 'B',
+        4 => 'C',
+        7 => 'F',
+        8 => 'I',
+        16 => 'R',
+        _ => 'P',
+    }
+}
+
+// symmorphic space group with Laue class and lattice type; the
+// centrosymmetric one if centric, otherwise the one with rotations
+// only
+fn representative(laue: Laue, lattice: char, centric: bool) -> Option<i32> {
+    let sg = match (laue, lattice, centric) {
+        (Laue::Triclinic, 'P', false) => 1,
+        (Laue::Triclinic, 'P', true) => 2,
+        (Laue::Monoclinic, 'P', false) => 3,
+        (Laue::Monoclinic, 'C', false) => 5,
+        (Laue::Monoclinic, 'P', true) => 10,
+        (Laue::Monoclinic, 'C', true) => 12,
+        (Laue::Orthorhombic, 'P', false) => 16,
+        (Laue::Orthorhombic, 'C', false) => 21,
+        (Laue::Orthorhombic, 'F', false) => 22,
+        (Laue::Orthorhombic, 'I', false) => 23,
+        (Laue::Orthorhombic, 'P', true) => 47,
+        (Laue::Orthorhombic, 'C', true) => 65,
+        (Laue::Orthorhombic, 'F', true) => 69,
+        (Laue::Orthorhombic, 'I', true) => 71,
+        (Laue::Tetragonal4m, 'P', false) => 75,
+        (Laue::Tetragonal4m, 'I', false) => 79,
+        (Laue::Tetragonal4m, 'P', true) => 83,
+        (Laue::Tetragonal4m, 'I', true) => 87,
+        (Laue::Tetragonal4mmm, 'P', false) => 89,
+        (Laue::Tetragonal4mmm, 'I', false) => 97,
+        (Laue::Tetragonal4mmm, 'P', true) => 123,
+        (Laue::Tetragonal4mmm, 'I', true) => 139,
+        (Laue::Trigonal3, 'P', false) => 143,
+        (Laue::Trigonal3, 'R', false) => 146,
+        (Laue::Trigonal3, 'P', true) => 147,
+        (Laue::Trigonal3, 'R', true) => 148,
+        (Laue::Trigonal31m, 'P', false) => 149,
+        (Laue::Trigonal31m, 'P', true) => 162,
+        (Laue::Trigonal3m1, 'P', false) => 150,
+        (Laue::Trigonal3m1, 'R', false) => 155,
+        (Laue::Trigonal3m1, 'P', true) => 164,
+        (Laue::Trigonal3m1, 'R', true) => 166,
+        (Laue::Hexagonal6m, 'P', false) => 168,
+        (Laue::Hexagonal6m, 'P', true) => 175,
+        (Laue::Hexagonal6mmm, 'P', false) => 177,
+        (Laue::Hexagonal6mmm, 'P', true) => 191,
+        (Laue::Cubicm3, 'P', false) => 195,
+        (Laue::Cubicm3, 'F', false) => 196,
+        (Laue::Cubicm3, 'I', false) => 197,
+        (Laue::Cubicm3, 'P', true) => 200,
+        (Laue::Cubicm3, 'F', true) => 202,
+        (Laue::Cubicm3, 'I', true) => 204,
+        (Laue::Cubicm3m, 'P', false) => 207,
+        (Laue::Cubicm3m, 'F', false) => 209,
+        (Laue::Cubicm3m, 'I', false) => 211,
+        (Laue::Cubicm3m, 'P', true) => 221,
+        (Laue::Cubicm3m, 'F', true) => 225,
+        (Laue::Cubicm3m, 'I', true) => 229,
+        _ => return None,
+    };
+    Some(sg)
+}
+
+/// highest common subgroup of the space groups in sgs, with respect
+/// to Laue class and lattice type. Screw axes and glide planes do not
+/// matter for scaling and are dropped. None for an empty or invalid
+/// list
+pub fn common_subgroup(sgs: &[i32]) -> Option<i32> {
+    let mut common: Vec<Laue> = laue(*sgs.first()?)?.subgroups();
+    let mut centr: u8 = 0xff;
+    let mut centric = true;
+    for &sg in sgs {
+        let sub = laue(sg)?.subgroups();
+        common.retain(|l| sub.contains(l));
+        centr &= centring(lattice(sg));
+        centric &= centrosymmetric(sg);
+    }
+    let highest = common.iter().max_by_key(|l| l.order())?;
+    let lat = lattice_from_centring(centr);
+    representative(*highest, lat, centric).or(representative(*highest, 'P', centric))
+}
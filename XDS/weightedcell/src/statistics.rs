@@ -0,0 +1,154 @@
+//! Data quality per resolution shell, from the final table "SUBSET OF
+//! INTENSITY DATA WITH SIGNAL/NOISE >= -3.0 AS FUNCTION OF RESOLUTION"
+//! of CORRECT.LP, and a summary to compare the data sets
+
+use crate::cell::Cell;
+
+/// one row of the table; percentages are given as in CORRECT.LP
+#[derive(Clone, Debug)]
+pub struct Shell {
+    /// high resolution limit in A
+    pub dmin: f64,
+    pub observed: u64,
+    pub unique: u64,
+    pub possible: u64,
+    pub completeness: f64,
+    pub r_observed: f64,
+    pub r_expected: f64,
+    pub compared: u64,
+    pub i_sigma: f64,
+    pub r_meas: f64,
+    pub cc_half: f64,
+    /// CORRECT marks CC(1/2) significant at the 0.1 % level with '*'
+    pub cc_half_significant: bool,
+    pub anomalous_corr: f64,
+    pub anomalous_significant: bool,
+    pub sig_ano: f64,
+    pub n_ano: u64,
+}
+
+/// shells from low to high resolution and the total over all shells
+#[derive(Clone, Debug)]
+pub struct Statistics {
+    pub shells: Vec<Shell>,
+    pub total: Shell,
+}
+
+/// header of the table in CORRECT.LP
+pub const TABLE: &str =
+    "SUBSET OF INTENSITY DATA WITH SIGNAL/NOISE >= -3.0 AS FUNCTION OF RESOLUTION";
+
+/// row of the table, None for other lines. The total row has the
+/// resolution limit 0
+pub fn shell(l: &str) -> Option<Shell> {
+    let w: Vec<&str> = l.split_whitespace().collect();
+    if w.len() != 14 {
+        return None;
+    }
+    let x = |i: usize| w[i].trim_end_matches(['%', '*']).parse::<f64>().ok();
+    let n = |i: usize| w[i].parse::<u64>().ok();
+    let dmin = if w[0] == "total" { Some(0.0) } else { x(0) };
+    Some(Shell {
+        dmin: dmin?,
+        observed: n(1)?,
+        unique: n(2)?,
+        possible: n(3)?,
+        completeness: x(4)?,
+        r_observed: x(5)?,
+        r_expected: x(6)?,
+        compared: n(7)?,
+        i_sigma: x(8)?,
+        r_meas: x(9)?,
+        cc_half: x(10)?,
+        cc_half_significant: w[10].ends_with('*'),
+        anomalous_corr: x(11)?,
+        anomalous_significant: w[11].ends_with('*'),
+        sig_ano: x(12)?,
+        n_ano: n(13)?,
+    })
+}
+
+/// overall and highest shell values of the data sets with
+/// statistics, as XSCALE.INP comments
+pub fn summary(cells: &[Cell]) -> String {
+    let mut out = String::from("! Data quality from CORRECT.LP, overall (highest shell):\n");
+    out += &format!(
+        "! {:>5} {:>13} {:>14} {:>15} {:>15} {:>11} {:>6}  data set\n",
+        "dmin", "compl/%", "I/sigma", "Rmeas/%", "CC1/2", "AnoCorr", "SigAno"
+    );
+    for cell in cells {
+        let Some(s) = &cell.statistics else {
+            continue;
+        };
+        let (t, h) = (&s.total, s.shells.last().unwrap_or(&s.total));
+        let star = |b: bool| if b { '*' } else { ' ' };
+        out += &format!(
+            "! {:5.2} {:5.1} ({:5.1}) {:6.2} ({:5.2}) {:6.1} ({:6.1}) {:5.1}{} ({:5.1}{}) {:4} ({:4}) {:6.3}  {}\n",
+            h.dmin,
+            t.completeness,
+            h.completeness,
+            t.i_sigma,
+            h.i_sigma,
+            t.r_meas,
+            h.r_meas,
+            t.cc_half,
+            star(t.cc_half_significant),
+            h.cc_half,
+            star(h.cc_half_significant),
+            t.anomalous_corr,
+            h.anomalous_corr,
+            t.sig_ano,
+            cell.file
+        );
+    }
+    out + "!\n"
+}
+
+/// criterion for the high resolution limit of a data set
+#[derive(Clone, Copy, PartialEq)]
+pub enum Criterion {
+    /// CC(1/2) significant at the 0.1 % level
+    CcHalf,
+    /// I/sigma at least the given value
+    ISigma(f64),
+}
+
+impl Criterion {
+    fn passes(&self, s: &Shell) -> bool {
+        match self {
+            Criterion::CcHalf => s.cc_half_significant,
+            Criterion::ISigma(min) => s.i_sigma >= *min,
+        }
+    }
+
+    // why shell s fails
+    fn reason(&self, s: &Shell) -> String {
+        match self {
+            Criterion::CcHalf => format!(
+                "CC1/2 = {:.1} at {:.2} A not significant at the 0.1 % level",
+                s.cc_half, s.dmin
+            ),
+            Criterion::ISigma(min) => {
+                format!(
+                    "I/sigma = {:.2} at {:.2} A below {min:.2}",
+                    s.i_sigma, s.dmin
+                )
+            }
+        }
+    }
+}
+
+/// high resolution limit after criterion: the shells are taken from
+/// low resolution up to the first one that fails. Returns the limit,
+/// None if all shells pass or already the first one fails, and the
+/// reason
+pub fn cutoff(stats: &Statistics, criterion: Criterion) -> (Option<f64>, String) {
+    let Some(n) = stats.shells.iter().position(|s| !criterion.passes(s)) else {
+        return (None, String::from("all resolution shells pass"));
+    };
+    let reason = criterion.reason(&stats.shells[n]);
+    match n {
+        0 => (None, reason),
+        _ => (Some(stats.shells[n - 1].dmin), reason),
+    }
+}
@@ -0,0 +1,79 @@
+//! Values with standard uncertainties in the notation of the IUCr,
+//! e.g. 57.822(15). The s.u. is rounded by the "rule of 19": two
+//! significant digits if they do not exceed 19, one digit otherwise.
+//! The value is rounded to the same decimal place
+
+/// decimal places of a value with s.u. su and the rounded s.u. in
+/// units of the last place, e.g. (3, 15) for 0.0153 and (-2, 2) for
+/// 233; None if su is not positive
+pub fn round(su: f64) -> Option<(i32, i64)> {
+    if !(su > 0.0 && su.is_finite()) {
+        return None;
+    }
+    // decimal places for two significant digits
+    let places = 1 - su.log10().floor() as i32;
+    let two = (su * 10f64.powi(places)).round() as i64;
+    if two <= 19 {
+        return Some((places, two));
+    }
+    // rounding may give 10, which is within the rule
+    let one = (su * 10f64.powi(places - 1)).round() as i64;
+    Some((places - 1, one))
+}
+
+/// value with its s.u. in parentheses. Values without s.u., i.e.
+/// fixed by symmetry (0) or unknown (negative), are written with up
+/// to four decimals
+pub fn format(x: f64, su: f64) -> String {
+    let Some((places, digits)) = round(su) else {
+        let s = format!("{x:.4}");
+        return s.trim_end_matches('0').trim_end_matches('.').to_string();
+    };
+    if places >= 0 {
+        let p = places as usize;
+        return format!("{x:.p$}({digits})");
+    }
+    // s.u. of 2 or more in front of the decimal point, e.g. 502800(200)
+    let unit = 10f64.powi(-places);
+    let x = (x / unit).round() * unit;
+    format!("{x:.0}({})", digits * unit as i64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rule_of_19() {
+        // two digits up to 19, one digit above, also when rounding
+        // crosses a power of ten
+        assert_eq!(round(0.0153), Some((3, 15)));
+        assert_eq!(round(0.0195), Some((2, 2)));
+        assert_eq!(round(0.095), Some((2, 10)));
+        assert_eq!(round(0.0999), Some((2, 10)));
+        assert_eq!(round(1.5), Some((1, 15)));
+        assert_eq!(round(19.6), Some((-1, 2)));
+        assert_eq!(round(233.0), Some((-2, 2)));
+    }
+
+    #[test]
+    fn formatted() {
+        assert_eq!(format(57.82216, 0.0153), "57.822(15)");
+        assert_eq!(format(57.82216, 0.0195), "57.82(2)");
+        assert_eq!(format(57.82216, 0.095), "57.82(10)");
+        assert_eq!(format(57.82216, 0.0999), "57.82(10)");
+        assert_eq!(format(57.82216, 1.5), "57.8(15)");
+        assert_eq!(format(502.7, 19.6), "500(20)");
+        assert_eq!(format(502812.0, 233.0), "502800(200)");
+    }
+
+    #[test]
+    fn without_su() {
+        // fixed by symmetry or unknown: up to four decimals, no zeros
+        assert_eq!(round(0.0), None);
+        assert_eq!(round(-1.0), None);
+        assert_eq!(format(90.0, 0.0), "90");
+        assert_eq!(format(57.82216, -1.0), "57.8222");
+        assert_eq!(format(78.1, -1.0), "78.1");
+    }
+}
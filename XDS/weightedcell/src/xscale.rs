@@ -0,0 +1,209 @@
+//! XSCALE.INP with the mean cell and the input data sets
+
+use crate::average::{Average, SuMode, WMean};
+use crate::cell::{cell_volume, Cell};
+use crate::niggli;
+use crate::reindex::{self, Reindexing};
+use crate::spacegroup;
+use crate::statistics::{self, Criterion};
+use crate::su;
+
+/// whether a data set is an INPUT_FILE of XSCALE.INP, or why it is
+/// commented out
+#[derive(Clone, Copy, PartialEq)]
+pub enum Input {
+    Merge,
+    Outlier,
+    LowIsa,
+}
+
+// cell of one data set; the volume is compared with the one of
+// the mean cell, vmean
+fn printcell(out: &mut String, cell: &Cell, vmean: f64) {
+    *out += &format!("!---> {}\n", cell.file);
+    *out += &format!("!     from {}\n", cell.source.describe());
+    if let Some(scan) = cell.scan.describe() {
+        *out += &format!("!     scan {scan}\n");
+    }
+    if let Some(law) = cell.scan.friedels_law {
+        *out += &format!(
+            "!     Friedel's law {}\n",
+            if law { "TRUE" } else { "FALSE" }
+        );
+    }
+    if let Some(e) = cell.error_model {
+        *out += &format!(
+            "!     ISa {:7.2}, error model a= {:.3} b= {:.3e}\n",
+            e.isa, e.a, e.b
+        );
+    }
+    *out += &format!(
+        "!     cell {:>12}{:>12}{:>12}{:>12}{:>12}{:>12}\n",
+        su::format(cell.a, cell.a_esu),
+        su::format(cell.b, cell.b_esu),
+        su::format(cell.c, cell.c_esu),
+        su::format(cell.alpha, cell.alpha_esu),
+        su::format(cell.beta, cell.beta_esu),
+        su::format(cell.gamma, cell.gamma_esu)
+    );
+    let (vol, vol_esu) = cell_volume(cell);
+    *out += &format!(
+        "!     volume {} A^3, {:+.2} % from mean\n",
+        su::format(vol, vol_esu),
+        100.0 * (vol - vmean) / vmean
+    );
+    let (reduced, p) = niggli::reduce(cell);
+    *out += &format!(
+        "!     Niggli{:8.3}{:8.3}{:8.3}{:9.3}{:9.3}{:9.3}\n",
+        reduced.a, reduced.b, reduced.c, reduced.alpha, reduced.beta, reduced.gamma
+    );
+    *out += &format!(
+        "!     P   {:3}{:3}{:3} /{:3}{:3}{:3} /{:3}{:3}{:3}   (a',b',c') = {}{}\n",
+        p[0][0],
+        p[0][1],
+        p[0][2],
+        p[1][0],
+        p[1][1],
+        p[1][2],
+        p[2][0],
+        p[2][1],
+        p[2][2],
+        niggli::basis(&p),
+        match spacegroup::lattice(cell.sg) {
+            'P' | '?' => String::new(),
+            l => format!(" of primitive {l}"),
+        }
+    );
+}
+
+// replace CORRECT.LP with XDS_ASCII.HKL and print with REIDX
+// if reindexed and with INCLUDE_RESOLUTION_RANGE after cutoff; data
+// sets not to be merged are commented out, alternative indexings are
+// listed as comments
+fn printinp(
+    out: &mut String,
+    cell: &Cell,
+    input: Input,
+    reidx: &Reindexing,
+    cutoff: Option<Criterion>,
+) {
+    let s = cell.file.replace("CORRECT.LP", "XDS_ASCII.HKL");
+    let c = match input {
+        Input::Merge => {
+            *out += &format!(" INPUT_FILE= {s}\n");
+            ' '
+        }
+        Input::Outlier => {
+            *out += &format!("!INPUT_FILE= {s}     ! rejected as outlier\n");
+            '!'
+        }
+        Input::LowIsa => {
+            *out += &format!("!INPUT_FILE= {s}     ! ISa below the minimum\n");
+            '!'
+        }
+    };
+    if reidx.p != niggli::IDENTITY {
+        *out += &format!("{c}{}\n", reindex::reidx(&reidx.p));
+    }
+    if let (Some(criterion), Some(stats)) = (cutoff, &cell.statistics) {
+        match statistics::cutoff(stats, criterion) {
+            (Some(dmin), reason) => {
+                *out += &format!("{c}INCLUDE_RESOLUTION_RANGE= 999.0 {dmin:.2}     ! {reason}\n")
+            }
+            (None, reason) => *out += &format!("!    no resolution cutoff: {reason}\n"),
+        }
+    }
+    for (p, cc) in &reidx.alternatives {
+        *out += &format!("!{}     ! alternative indexing", reindex::reidx(p));
+        if let Some(cc) = cc {
+            *out += &format!(", CC= {cc:.3}");
+        }
+        *out += "\n";
+    }
+}
+
+/// summarise information as valid XSCALE.INP
+#[allow(clippy::too_many_arguments)]
+pub fn xscaleinp(
+    out: &mut String,
+    cells: Vec<Cell>,
+    inputs: &[Input],
+    reidx: &[Reindexing],
+    mcell: Cell,
+    stats: &[WMean; 6],
+    mode: SuMode,
+    average: Average,
+    cutoff: Option<Criterion>,
+    hklout: &str,
+) {
+    let (vol, vol_esu) = cell_volume(&mcell);
+    if cells.iter().any(|c| c.statistics.is_some()) {
+        *out += &statistics::summary(&cells);
+    }
+    for c in &cells {
+        printcell(out, c, vol);
+    }
+    *out += "!=========================================================================\n";
+    *out += &format!(
+        "! Mean cell: {:8.4} {:8.4} {:8.4} {:9.3} {:9.3} {:9.3}\n",
+        mcell.a, mcell.b, mcell.c, mcell.alpha, mcell.beta, mcell.gamma
+    );
+    *out += &format!(
+        "!   e.s.u's: {:8.4} {:8.4} {:8.4} {:8.3} {:8.3} {:8.3}\n",
+        mcell.a_esu, mcell.b_esu, mcell.c_esu, mcell.alpha_esu, mcell.beta_esu, mcell.gamma_esu
+    );
+    *out += &format!(
+        "! with s.u.: {} {} {} {} {} {}\n",
+        su::format(mcell.a, mcell.a_esu),
+        su::format(mcell.b, mcell.b_esu),
+        su::format(mcell.c, mcell.c_esu),
+        su::format(mcell.alpha, mcell.alpha_esu),
+        su::format(mcell.beta, mcell.beta_esu),
+        su::format(mcell.gamma, mcell.gamma_esu)
+    );
+    *out += &format!("!    volume: {} A^3\n", su::format(vol, vol_esu));
+    let vols: Vec<f64> = cells
+        .iter()
+        .zip(inputs)
+        .filter(|(_, i)| **i == Input::Merge)
+        .map(|(c, _)| cell_volume(c).0)
+        .collect();
+    if vols.len() > 1 {
+        let rms =
+            f64::sqrt(vols.iter().map(|v| (v - vol).powi(2)).sum::<f64>() / vols.len() as f64);
+        *out += &format!(
+            "!   volumes of the data sets {:.1} - {:.1} A^3, rms deviation {:.1} ({:.2} %)\n",
+            vols.iter().copied().fold(f64::INFINITY, f64::min),
+            vols.iter().copied().fold(-f64::INFINITY, f64::max),
+            rms,
+            100.0 * rms / vol
+        );
+    }
+    *out += &format!("!   averaged over the {}\n", average.describe());
+    let row = |f: fn(&WMean) -> f64| -> String {
+        let v: Vec<f64> = stats.iter().map(f).collect();
+        format!(
+            "{:8.4} {:8.4} {:8.4} {:8.3} {:8.3} {:8.3}",
+            v[0], v[1], v[2], v[3], v[4], v[5]
+        )
+    };
+    // the unit weights of a single data set give no statistics
+    if mode == SuMode::Unknown {
+        *out += "!   no e.s.u's, from a single data set without e.s.u's\n";
+    } else {
+        *out += &format!("!   e.s.u's are the {}\n", mode.describe());
+        *out += &format!("!  internal: {}\n", row(|m| m.internal));
+        *out += &format!("!  external: {}\n", row(|m| m.external));
+        *out += &format!("! red. chi2: {}\n", row(|m| m.chi2));
+    }
+
+    *out += &format!("\n OUTPUT_FILE= {hklout}\n");
+    *out += &format!("\n SPACE_GROUP_NUMBER= {}\n", mcell.sg);
+    *out += &format!(
+        " UNIT_CELL_CONSTANTS= {:8.4} {:8.4} {:8.4} {:8.3} {:8.3} {:8.3}\n",
+        mcell.a, mcell.b, mcell.c, mcell.alpha, mcell.beta, mcell.gamma
+    );
+    for ((c, i), r) in cells.iter().zip(inputs).zip(reidx) {
+        printinp(out, c, *i, r, cutoff);
+    }
+}